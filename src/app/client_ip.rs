@@ -0,0 +1,145 @@
+use forwarded_header_value::ForwardedHeaderValue;
+use http::HeaderMap;
+use std::net::IpAddr;
+
+use super::config::{ClientIpConfig, ClientIpHeader};
+
+impl ClientIpHeader {
+    fn name(&self) -> &'static str {
+        match self {
+            ClientIpHeader::XForwardedFor => "x-forwarded-for",
+            ClientIpHeader::Forwarded => "forwarded",
+            ClientIpHeader::XRealIp => "x-real-ip",
+            ClientIpHeader::CfConnectingIp => "cf-connecting-ip",
+        }
+    }
+}
+
+impl ClientIpConfig {
+    fn is_trusted(&self, ip_address: &IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|net| net.contains(ip_address))
+    }
+
+    /// Resolves an ip address of the client connected from the peer address.
+    ///
+    /// Headers are only read if the peer is a trusted proxy. A chain of
+    /// `X-Forwarded-For` or `Forwarded` is walked from the right skipping
+    /// trusted proxies so that addresses prepended by the client are ignored.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        let mut values = headers
+            .get_all(self.header.name())
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        let chain = match self.header {
+            ClientIpHeader::XForwardedFor => values
+                .flat_map(|value| value.split(','))
+                .filter_map(|ip_address| ip_address.trim().parse().ok())
+                .collect::<Vec<IpAddr>>(),
+            ClientIpHeader::Forwarded => values
+                .filter_map(|value| ForwardedHeaderValue::from_forwarded(value).ok())
+                .flat_map(|value| {
+                    value
+                        .iter()
+                        .filter_map(|stanza| stanza.forwarded_for_ip())
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            ClientIpHeader::XRealIp | ClientIpHeader::CfConnectingIp => values
+                .next_back()
+                .and_then(|ip_address| ip_address.trim().parse().ok())
+                .into_iter()
+                .collect(),
+        };
+
+        chain
+            .iter()
+            .rev()
+            .find(|ip_address| !self.is_trusted(ip_address))
+            .or_else(|| chain.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn config(header: ClientIpHeader) -> ClientIpConfig {
+        ClientIpConfig {
+            header,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn resolve_untrusted_peer() {
+        let config = config(ClientIpHeader::XForwardedFor);
+        let headers = headers("x-forwarded-for", "192.0.2.1");
+
+        assert_eq!(
+            config.resolve(ip("198.51.100.1"), &headers),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            ClientIpConfig::default().resolve(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn resolve_forwarded_chain() {
+        let config = config(ClientIpHeader::XForwardedFor);
+        let peer = ip("10.0.0.1");
+
+        let spoofed = headers("x-forwarded-for", "192.0.2.1, 198.51.100.1, 10.0.0.2");
+        assert_eq!(config.resolve(peer, &spoofed), ip("198.51.100.1"));
+
+        let internal = headers("x-forwarded-for", "10.0.0.3, 10.0.0.2");
+        assert_eq!(config.resolve(peer, &internal), ip("10.0.0.3"));
+
+        assert_eq!(config.resolve(peer, &HeaderMap::new()), peer);
+
+        let config = self::config(ClientIpHeader::Forwarded);
+        let forwarded = headers("forwarded", "for=192.0.2.1;proto=https, for=10.0.0.2");
+        assert_eq!(config.resolve(peer, &forwarded), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn resolve_single_header() {
+        let peer = ip("10.0.0.1");
+
+        let config = config(ClientIpHeader::XRealIp);
+        assert_eq!(
+            config.resolve(peer, &headers("x-real-ip", "192.0.2.1")),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            config.resolve(peer, &headers("x-forwarded-for", "192.0.2.1")),
+            peer
+        );
+
+        let config = self::config(ClientIpHeader::CfConnectingIp);
+        assert_eq!(
+            config.resolve(peer, &headers("cf-connecting-ip", "192.0.2.1")),
+            ip("192.0.2.1")
+        );
+    }
+}
@@ -1,5 +1,6 @@
+use ipnet::IpNet;
 use serde::Deserialize;
-use std::{collections::BTreeMap, net::SocketAddr};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, time::Duration};
 use url::Url;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -10,16 +11,143 @@ pub struct AppConfig {
     pub authz: svc_authz::ConfigMap,
     pub http: HttpConfig,
     pub audiences_settings: BTreeMap<String, AudienceSettings>,
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub maxmind: MaxmindConfig,
+    #[serde(default)]
+    pub country_override: CountryOverrideConfig,
+    /// URIs of http authz clients by audience, `svc_authz::Config` keeps them private.
+    #[serde(skip)]
+    pub authz_endpoints: BTreeMap<String, String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Debug, Deserialize)]
+struct AuthzEndpointConfig {
+    uri: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct HttpConfig {
     pub listener_address: SocketAddr,
+    /// Time between failing readiness checks and closing the listener
+    /// on shutdown to let load balancers stop routing requests.
+    #[serde(
+        default = "HttpConfig::default_shutdown_delay",
+        deserialize_with = "crate::serde::duration"
+    )]
+    pub shutdown_delay: Duration,
+    /// Time to wait for in-flight requests to complete on shutdown.
+    #[serde(
+        default = "HttpConfig::default_drain_timeout",
+        deserialize_with = "crate::serde::duration"
+    )]
+    pub drain_timeout: Duration,
+    #[serde(default)]
+    pub client_ip: ClientIpConfig,
+}
+
+impl HttpConfig {
+    fn default_shutdown_delay() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_drain_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+/// How an ip address of the client is resolved behind proxies.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClientIpConfig {
+    /// A header proxies pass the client address in.
+    pub header: ClientIpHeader,
+    /// Networks of proxies the header is trusted from, it's ignored if empty.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+    XRealIp,
+    CfConnectingIp,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MaxmindConfig {
+    /// A path to the country database.
+    pub path: PathBuf,
+    /// A path to the ASN database enabling routing by autonomous system.
+    pub asn_path: Option<PathBuf>,
+    /// Time between checks of databases being replaced.
+    #[serde(deserialize_with = "crate::serde::duration")]
+    pub check_interval: Duration,
+}
+
+impl Default for MaxmindConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("maxmind.mmdb"),
+            asn_path: None,
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Lets internal tools pass a country of the client instead of the resolved one.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CountryOverrideConfig {
+    pub header: String,
+    /// Accounts the header is trusted from, it's ignored if empty.
+    pub accounts: Vec<svc_authn::AccountId>,
+}
+
+impl Default for CountryOverrideConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-country-override".to_owned(),
+            accounts: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    pub http: MetricsHttpConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MetricsHttpConfig {
+    pub bind_address: SocketAddr,
+}
+
+/// How objects are served by the read endpoint.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadMode {
+    /// Redirect to a signed URL of the object.
+    #[default]
+    Redirect,
+    /// Stream the object through the service.
+    Stream,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct AudienceSettings {
     allowed_referers: Option<Vec<String>>,
+    /// Overrides the read mode of the backend for buckets of the audience.
+    read_mode: Option<ReadMode>,
+    /// A backend or an alias requests without a backend in the path are served by.
+    default_backend: Option<String>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    min_expires_in: Option<Duration>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    max_expires_in: Option<Duration>,
 }
 
 impl AppConfig {
@@ -28,7 +156,19 @@ impl AppConfig {
             .add_source(config::File::with_name("App"))
             .add_source(config::Environment::with_prefix("APP"))
             .build()
-            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .and_then(Self::from_config)
+    }
+
+    fn from_config(config: config::Config) -> Result<AppConfig, config::ConfigError> {
+        let authz_endpoints = config
+            .get::<BTreeMap<String, AuthzEndpointConfig>>("authz")?
+            .into_iter()
+            .filter_map(|(audience, authz)| Some((audience, authz.uri?)))
+            .collect();
+
+        let mut app_config = config.try_deserialize::<AppConfig>()?;
+        app_config.authz_endpoints = authz_endpoints;
+        Ok(app_config)
     }
 }
 
@@ -55,6 +195,27 @@ impl AudienceSettings {
             }
         }
     }
+
+    pub fn read_mode(&self) -> Option<ReadMode> {
+        self.read_mode
+    }
+
+    pub fn default_backend(&self) -> Option<&str> {
+        self.default_backend.as_deref()
+    }
+
+    /// Clamps requested expiration time of a signature to the audience bounds.
+    pub fn clamp_expires_in(&self, expires_in: Duration) -> Duration {
+        let expires_in = match self.min_expires_in {
+            Some(min) => expires_in.max(min),
+            None => expires_in,
+        };
+
+        match self.max_expires_in {
+            Some(max) => expires_in.min(max),
+            None => expires_in,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -65,6 +226,10 @@ mod tests {
     fn valid_referer_no_refs() {
         let s = AudienceSettings {
             allowed_referers: None,
+            read_mode: None,
+            default_backend: None,
+            min_expires_in: None,
+            max_expires_in: None,
         };
         assert!(s.valid_referer(None));
         assert!(s.valid_referer(Some("foobar")));
@@ -74,6 +239,10 @@ mod tests {
     fn valid_referer_no_referer() {
         let s = AudienceSettings {
             allowed_referers: Some(vec!["foo".into(), "bar".into(), "baz".into()]),
+            read_mode: None,
+            default_backend: None,
+            min_expires_in: None,
+            max_expires_in: None,
         };
         assert!(!s.valid_referer(None));
         assert!(s.valid_referer(Some("http://foo")));
@@ -85,6 +254,10 @@ mod tests {
     fn valid_referer_mask() {
         let s = AudienceSettings {
             allowed_referers: Some(vec!["*.foo".into()]),
+            read_mode: None,
+            default_backend: None,
+            min_expires_in: None,
+            max_expires_in: None,
         };
         assert!(!s.valid_referer(None));
         assert!(s.valid_referer(Some("http://baz.foo")));
@@ -92,4 +265,78 @@ mod tests {
         assert!(!s.valid_referer(Some("http://qwe.quux")));
         assert!(!s.valid_referer(Some("http://foo")));
     }
+
+    #[test]
+    fn clamp_expires_in() {
+        let s = AudienceSettings {
+            allowed_referers: None,
+            read_mode: None,
+            default_backend: None,
+            min_expires_in: Some(Duration::from_secs(60)),
+            max_expires_in: Some(Duration::from_secs(86400)),
+        };
+        assert_eq!(
+            s.clamp_expires_in(Duration::from_secs(10)),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            s.clamp_expires_in(Duration::from_secs(300)),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            s.clamp_expires_in(Duration::from_secs(172800)),
+            Duration::from_secs(86400)
+        );
+    }
+
+    #[test]
+    fn clamp_expires_in_no_bounds() {
+        let s = AudienceSettings {
+            allowed_referers: None,
+            read_mode: None,
+            default_backend: None,
+            min_expires_in: None,
+            max_expires_in: None,
+        };
+        assert_eq!(
+            s.clamp_expires_in(Duration::from_secs(172800)),
+            Duration::from_secs(172800)
+        );
+    }
+
+    #[test]
+    fn from_config_authz_endpoints() {
+        let config = r#"
+            id = "storage.svc.example.org"
+            authn = {}
+            backend = {}
+            audiences_settings = {}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [authz."a.example.org"]
+            type = "http"
+            uri = "http://authz.example.org/api/v1/authz"
+            algorithm = "ES256"
+            key = "data/keys/svc.private_key.p8.der.sample"
+
+            [authz."b.example.org"]
+            type = "local"
+            trusted = []
+        "#;
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(config, config::FileFormat::Toml))
+            .build()
+            .and_then(AppConfig::from_config)
+            .expect("config");
+
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "a.example.org".to_string(),
+            "http://authz.example.org/api/v1/authz".to_string(),
+        );
+        assert_eq!(config.authz_endpoints, expected);
+    }
 }
@@ -1,13 +1,26 @@
-use std::{collections::BTreeMap, env::var, sync::Arc};
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use axum::extract::FromRef;
+use std::{
+    collections::BTreeMap,
+    env::var,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 use svc_authn::AccountId;
 use svc_authz::{
-    cache::{create_pool, AuthzCache, RedisCache},
-    ClientMap,
+    cache::{create_pool, AuthzCache, ConnectionPool, RedisCache},
+    ClientMap, ErrorKind as AuthzErrorKind, IntentObject,
 };
+use tracing::warn;
 
 use crate::app::{
     config::{AppConfig, AudienceSettings},
-    util::{read_s3_config, AudienceEstimator, S3Clients},
+    metrics::METRICS,
+    util::{read_s3_config, update_s3_config, AudienceEstimator, S3Clients},
 };
 
 type S3ClientRef = Arc<S3Clients>;
@@ -19,66 +32,328 @@ pub struct AppContext {
     pub aud_estm: Arc<AudienceEstimator>,
     pub s3: S3ClientRef,
     pub audiences_settings: BTreeMap<String, AudienceSettings>,
+    pub authz_endpoints: BTreeMap<String, String>,
+    pub cache: Option<AuthzCachePool>,
 }
 
-impl AppContext {
-    pub fn build(config: AppConfig) -> Self {
-        let cache = var("CACHE_ENABLED")
-            .ok()
-            .and_then(|val| match val.as_ref() {
-                "1" => {
-                    let url =
-                        var("CACHE_URL").unwrap_or_else(|_| panic!("Missing CACHE_URL variable"));
-
-                    let size = var("CACHE_POOL_SIZE")
-                        .map(|val| {
-                            val.parse::<u32>()
-                                .expect("Error converting CACHE_POOL_SIZE variable into u32")
-                        })
-                        .unwrap_or_else(|_| 5);
-                    let idle_size = var("CACHE_POOL_IDLE_SIZE")
-                        .map(|val| {
-                            val.parse::<u32>()
-                                .expect("Error converting CACHE_POOL_IDLE_SIZE variable into u32")
-                        })
-                        .ok();
-                    let timeout = var("CACHE_POOL_TIMEOUT")
-                        .map(|val| {
-                            val.parse::<u64>()
-                                .expect("Error converting CACHE_POOL_TIMEOUT variable into u64")
-                        })
-                        .unwrap_or_else(|_| 5);
-                    let expiration_time = var("CACHE_EXPIRATION_TIME")
-                        .map(|val| {
-                            val.parse::<u64>()
-                                .expect("Error converting CACHE_EXPIRATION_TIME variable into u64")
-                        })
-                        .unwrap_or_else(|_| 300);
-
-                    Some(Box::new(RedisCache::new(
-                        create_pool(&url, size, idle_size, timeout),
-                        expiration_time as usize,
-                    )) as Box<dyn AuthzCache>)
-                }
-                _ => None,
-            });
+/// Redis connection pool of the authz cache shared between config reloads.
+#[derive(Clone, Debug)]
+pub struct AuthzCachePool {
+    pub pool: ConnectionPool,
+    expiration_time: usize,
+}
+
+impl AuthzCachePool {
+    fn authz_cache(&self) -> Box<dyn AuthzCache> {
+        Box::new(RedisCache::new(self.pool.clone(), self.expiration_time))
+    }
+}
+
+pub fn build_cache() -> Option<AuthzCachePool> {
+    var("CACHE_ENABLED")
+        .ok()
+        .and_then(|val| match val.as_ref() {
+            "1" => {
+                let url = var("CACHE_URL").unwrap_or_else(|_| panic!("Missing CACHE_URL variable"));
 
+                let size = var("CACHE_POOL_SIZE")
+                    .map(|val| {
+                        val.parse::<u32>()
+                            .expect("Error converting CACHE_POOL_SIZE variable into u32")
+                    })
+                    .unwrap_or_else(|_| 5);
+                let idle_size = var("CACHE_POOL_IDLE_SIZE")
+                    .map(|val| {
+                        val.parse::<u32>()
+                            .expect("Error converting CACHE_POOL_IDLE_SIZE variable into u32")
+                    })
+                    .ok();
+                let timeout = var("CACHE_POOL_TIMEOUT")
+                    .map(|val| {
+                        val.parse::<u64>()
+                            .expect("Error converting CACHE_POOL_TIMEOUT variable into u64")
+                    })
+                    .unwrap_or_else(|_| 5);
+                let expiration_time = var("CACHE_EXPIRATION_TIME")
+                    .map(|val| {
+                        val.parse::<u64>()
+                            .expect("Error converting CACHE_EXPIRATION_TIME variable into u64")
+                    })
+                    .unwrap_or_else(|_| 300);
+
+                Some(AuthzCachePool {
+                    pool: create_pool(&url, size, idle_size, timeout),
+                    expiration_time: expiration_time as usize,
+                })
+            }
+            _ => None,
+        })
+}
+
+impl AppContext {
+    pub fn build(config: AppConfig, cache: Option<AuthzCachePool>) -> Result<Self> {
         // Resources
-        let s3_clients = read_s3_config(&config.backend).expect("Error reading s3 config");
+        let s3_clients = read_s3_config(&config.backend).context("Error reading s3 config")?;
 
+        Self::build_with_clients(config, cache, s3_clients)
+    }
+
+    fn build_with_clients(
+        config: AppConfig,
+        cache: Option<AuthzCachePool>,
+        s3_clients: S3Clients,
+    ) -> Result<Self> {
         let s3 = S3ClientRef::new(s3_clients);
+        for client in s3.values() {
+            client.spawn_proxy_health_checks();
+        }
+
+        for (audience, aud_settings) in &config.audiences_settings {
+            if let Some(back) = aud_settings.default_backend() {
+                if s3.get(back).is_none() {
+                    bail!(
+                        "Default backend '{}' of the audience '{}' is not found",
+                        back,
+                        audience
+                    );
+                }
+            }
+        }
 
         // Authz
         let aud_estm = Arc::new(AudienceEstimator::new(&config.authz));
-        let authz = ClientMap::new(&config.id, cache, config.authz.clone(), None)
-            .expect("Error converting authz config to clients");
+        let authz_cache = cache.as_ref().map(AuthzCachePool::authz_cache);
+        let authz = ClientMap::new(&config.id, authz_cache, config.authz.clone(), None)
+            .context("Error converting authz config to clients")?;
 
-        Self {
+        Ok(Self {
             application_id: config.id.clone(),
             authz,
             aud_estm,
             s3,
             audiences_settings: config.audiences_settings,
+            authz_endpoints: config.authz_endpoints,
+            cache,
+        })
+    }
+
+    /// Authorizes the action recording duration and outcome of the call.
+    pub async fn authorize(
+        &self,
+        audience: String,
+        subject: AccountId,
+        object: Box<dyn IntentObject>,
+        action: String,
+    ) -> Result<(), svc_authz::Error> {
+        let started_at = Instant::now();
+        let result = self
+            .authz
+            .authorize(audience.clone(), subject, object, action)
+            .await;
+
+        let outcome = match result {
+            Ok(_) => "allowed",
+            Err(ref err) => match err.kind() {
+                AuthzErrorKind::Forbidden(_) => "denied",
+                AuthzErrorKind::Network(_) => "network_error",
+                AuthzErrorKind::Internal(_) => "internal_error",
+            },
+        };
+        METRICS.observe_authz(&audience, outcome, started_at.elapsed());
+
+        result.map(|_| ())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A context replaced as a whole on config reload.
+///
+/// Handlers extract a snapshot of the context at the start of a request,
+/// so in-flight requests finish with the context they've started with.
+#[derive(Clone)]
+pub struct ContextHandle {
+    inner: Arc<ArcSwap<AppContext>>,
+    /// The config of the current context.
+    config: Arc<Mutex<AppConfig>>,
+    cache: Option<AuthzCachePool>,
+    shutdown: ShutdownFlag,
+}
+
+impl ContextHandle {
+    pub fn new(context: AppContext, config: AppConfig, cache: Option<AuthzCachePool>) -> Self {
+        Self {
+            inner: Arc::new(ArcSwap::from_pointee(context)),
+            config: Arc::new(Mutex::new(config)),
+            cache,
+            shutdown: ShutdownFlag::default(),
+        }
+    }
+
+    pub fn shutdown(&self) -> &ShutdownFlag {
+        &self.shutdown
+    }
+
+    pub fn load(&self) -> Arc<AppContext> {
+        self.inner.load_full()
+    }
+
+    /// Builds a context of the config and swaps the current one with it.
+    /// The current context is kept if the config is invalid.
+    ///
+    /// Clients of backends with unchanged config are carried over
+    /// to the new context along with their state.
+    pub fn reload(&self, config: AppConfig) -> Result<()> {
+        let mut current = self.config.lock().expect("config lock poisoned");
+        if current.id != config.id {
+            warn!("Changes of 'id' config are ignored until restart");
         }
+        if current.http != config.http {
+            warn!("Changes of 'http' config are ignored until restart");
+        }
+        if current.metrics != config.metrics {
+            warn!("Changes of 'metrics' config are ignored until restart");
+        }
+        if current.maxmind != config.maxmind {
+            warn!("Changes of 'maxmind' config are ignored until restart");
+        }
+        if current.country_override != config.country_override {
+            warn!("Changes of 'country_override' config are ignored until restart");
+        }
+
+        let s3_clients = update_s3_config(&config.backend, &current.backend, &self.load().s3)
+            .context("Error reading s3 config")?;
+        let context =
+            AppContext::build_with_clients(config.clone(), self.cache.clone(), s3_clients)?;
+        self.inner.store(Arc::new(context));
+        *current = config;
+        Ok(())
+    }
+}
+
+impl FromRef<ContextHandle> for Arc<AppContext> {
+    fn from_ref(handle: &ContextHandle) -> Self {
+        handle.load()
+    }
+}
+
+/// Raised once the service starts shutting down to fail readiness checks
+/// before the listener is closed.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownFlag(Arc<AtomicBool>);
+
+impl ShutdownFlag {
+    pub fn raise(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl FromRef<ContextHandle> for ShutdownFlag {
+    fn from_ref(handle: &ContextHandle) -> Self {
+        handle.shutdown.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(audience: &str, backend: &str) -> AppConfig {
+        let config = format!(
+            r#"
+            id = "storage.svc.example.org"
+            authn = {{}}
+            authz = {{}}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend]
+            {backend}
+
+            [audiences_settings."{audience}"]
+            allowed_referers = ["*.{audience}"]
+            "#,
+            audience = audience,
+            backend = backend,
+        );
+
+        config::Config::builder()
+            .add_source(config::File::from_str(&config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config")
+    }
+
+    #[test]
+    fn reload_swaps_context() {
+        let initial = config("a.example.org", "");
+        let ctx = AppContext::build(initial.clone(), None).expect("context");
+        let handle = ContextHandle::new(ctx, initial, None);
+        let snapshot = handle.load();
+
+        handle.reload(config("b.example.org", "")).expect("reload");
+
+        assert!(snapshot.audiences_settings.contains_key("a.example.org"));
+        assert!(handle
+            .load()
+            .audiences_settings
+            .contains_key("b.example.org"));
+    }
+
+    #[test]
+    fn reload_keeps_context_on_invalid_config() {
+        let initial = config("a.example.org", "");
+        let ctx = AppContext::build(initial.clone(), None).expect("context");
+        let handle = ContextHandle::new(ctx, initial, None);
+
+        let invalid = config("b.example.org", "nowhere = { region = \"test\" }");
+        assert!(handle.reload(invalid).is_err());
+        assert!(handle
+            .load()
+            .audiences_settings
+            .contains_key("a.example.org"));
+    }
+
+    #[test]
+    fn reload_reuses_unchanged_clients() {
+        let backend = |region: &str| {
+            format!(
+                r#"
+                [backend.kept]
+                endpoint = "http://localhost:9000"
+                region = "test"
+                credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+
+                [backend.changed]
+                endpoint = "http://localhost:9000"
+                region = "{}"
+                credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+                "#,
+                region
+            )
+        };
+        let initial = config("a.example.org", &backend("a"));
+        let ctx = AppContext::build(initial.clone(), None).expect("context");
+        let handle = ContextHandle::new(ctx, initial, None);
+        let snapshot = handle.load();
+
+        handle
+            .reload(config("a.example.org", &backend("b")))
+            .expect("reload");
+
+        let ctx = handle.load();
+        assert!(Arc::ptr_eq(
+            snapshot.s3.get("kept").unwrap(),
+            ctx.s3.get("kept").unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            snapshot.s3.get("changed").unwrap(),
+            ctx.s3.get("changed").unwrap()
+        ));
     }
 }
@@ -1,54 +1,320 @@
-use crate::app::{
-    context::AppContext,
-    error::{Error, ErrorKind},
+use crate::{
+    app::{
+        authz::AuthzObject,
+        config::ReadMode,
+        context::AppContext,
+        error::{Error, ErrorKind, ErrorKindExt},
+        metrics::METRICS,
+        util::Set,
+    },
+    s3::Client,
 };
+use anyhow::anyhow;
 use axum::response::{IntoResponse, Response};
-use http::header::HeaderValue;
-use std::sync::Arc;
-use tracing::error;
+use http::{
+    header::{HeaderValue, CONTENT_TYPE},
+    StatusCode,
+};
+use std::{sync::Arc, time::Duration};
+use svc_authn::AccountId;
+use tracing::{error, warn};
 
+#[allow(clippy::result_large_err)]
 pub fn valid_referer(
     ctx: &Arc<AppContext>,
     bucket: &str,
     referer: Option<&HeaderValue>,
 ) -> Result<(), Response> {
+    check_referer(ctx, bucket, referer).map_err(|err| {
+        error!("{}", err.detail());
+        err.into_response()
+    })
+}
+
+/// Same as `valid_referer` but reports an `Error` instead of a ready response.
+pub fn check_referer(
+    ctx: &Arc<AppContext>,
+    bucket: &str,
+    referer: Option<&HeaderValue>,
+) -> Result<(), Error> {
     let referer = match referer {
         None => None,
         Some(r) => match r.to_str() {
             Ok(r) => Some(r),
-            Err(err) => return Err(wrap_error(ErrorKind::RefererError, err.to_string())),
+            Err(err) => return Err(anyhow!(err.to_string()).kind(ErrorKind::RefererError)),
         },
     };
 
     match ctx.aud_estm.estimate(bucket) {
         Ok(aud) => match ctx.audiences_settings.get(aud) {
             Some(aud_settings) => if !aud_settings.valid_referer(referer) {
-                return Err(wrap_error(ErrorKind::RefererError, "Error reading 'REFERER' header".to_string()));
+                return Err(anyhow!("Error reading 'REFERER' header").kind(ErrorKind::RefererError));
             }
             None => {
-                return Err(wrap_error(
-                    ErrorKind::MissingAudienceSetting,
-                    format!("Error reading an object using Set API: Audience settings for bucket '{}' not found", &bucket)
-                ));
+                return Err(anyhow!(
+                    "Error reading an object using Set API: Audience settings for bucket '{}' not found", &bucket
+                ).kind(ErrorKind::MissingAudienceSetting));
             }
         }
         Err(err) =>
-            return Err(wrap_error(
-                ErrorKind::MissingAudienceSetting,
-                format!("Error reading an object using Set API: Audience estimate for bucket '{}' not found, err = {}", &bucket, err)
-            )),
+            return Err(anyhow!(
+                "Error reading an object using Set API: Audience estimate for bucket '{}' not found, err = {}", &bucket, err
+            ).kind(ErrorKind::MissingAudienceSetting)),
     }
 
     Ok(())
 }
 
+/// Resolves the backend and the set, checks the referer and authorizes
+/// the action on the set. Errors are reported with `kind` prefixed by `op`.
+#[allow(clippy::too_many_arguments)]
+pub async fn authorize_set(
+    ctx: &Arc<AppContext>,
+    back: &str,
+    set: &str,
+    sub: AccountId,
+    action: &str,
+    referer: Option<&HeaderValue>,
+    kind: ErrorKind,
+    op: &str,
+) -> Result<(Arc<Client>, Set), Box<Response>> {
+    let (s3, set_s) = resolve_set(ctx, back, set, referer, kind, op)?;
+    authorize_action(ctx, set, &set_s, sub, action, op).await?;
+    Ok((s3, set_s))
+}
+
+/// Resolves the backend and the set and checks the referer.
+pub fn resolve_set(
+    ctx: &Arc<AppContext>,
+    back: &str,
+    set: &str,
+    referer: Option<&HeaderValue>,
+    kind: ErrorKind,
+    op: &str,
+) -> Result<(Arc<Client>, Set), Box<Response>> {
+    let s3 = match ctx.s3.get(back) {
+        Some(val) => val.clone(),
+        None => {
+            return Err(Box::new(wrap_error(
+                ErrorKind::BackendNotFound,
+                format!("{}: Backend '{}' is not found", op, back),
+            )))
+        }
+    };
+
+    let set_s = match ctx.aud_estm.parse_set(set) {
+        Ok(set_s) => set_s,
+        Err(err) => return Err(Box::new(wrap_error(kind, format!("{}: {}", op, err)))),
+    };
+
+    valid_referer(ctx, &set_s.bucket().to_string(), referer).map_err(Box::new)?;
+
+    Ok((s3, set_s))
+}
+
+/// Authorizes the action on the set resolved with `resolve_set`.
+pub async fn authorize_action(
+    ctx: &Arc<AppContext>,
+    set: &str,
+    set_s: &Set,
+    sub: AccountId,
+    action: &str,
+    op: &str,
+) -> Result<(), Box<Response>> {
+    let zobj = AuthzObject::new(&["sets", set]);
+    if let Err(err) = ctx
+        .authorize(
+            set_s.bucket().audience().to_string(),
+            sub,
+            Box::new(zobj),
+            action.to_string(),
+        )
+        .await
+    {
+        return Err(Box::new(wrap_error(
+            ErrorKind::AccessDenied,
+            format!("{}: {}", op, err),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns expiration time of a signature clamped to the audience bounds.
+pub fn signature_expires_in(
+    ctx: &Arc<AppContext>,
+    s3: &Client,
+    set: &Set,
+    requested: Option<Duration>,
+) -> Duration {
+    let expires_in = requested.unwrap_or_else(|| s3.expires_in());
+    match ctx.audiences_settings.get(set.bucket().audience()) {
+        Some(aud_settings) => aud_settings.clamp_expires_in(expires_in),
+        None => expires_in,
+    }
+}
+
+/// A read mode of the audience of the set if specified or the backend one.
+pub fn read_mode(ctx: &Arc<AppContext>, s3: &Client, set: &Set) -> ReadMode {
+    ctx.audiences_settings
+        .get(set.bucket().audience())
+        .and_then(|aud_settings| aud_settings.read_mode())
+        .unwrap_or_else(|| s3.read_mode())
+}
+
+/// Time a backend is given to report whether it has an object before falling back.
+const FAILOVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Picks the backend or the first of its fallbacks the object is available on.
+///
+/// The backend itself is returned if it has no fallbacks or none of them has the object.
+/// A backend not responding within `FAILOVER_TIMEOUT` is considered not having it.
+pub async fn failover(
+    ctx: &Arc<AppContext>,
+    s3: Arc<Client>,
+    bucket: &str,
+    object: &str,
+) -> Arc<Client> {
+    if s3.fallbacks().is_empty() {
+        return s3;
+    }
+
+    if let Err(err) = head_object(&s3, bucket, object).await {
+        warn!(
+            "Object '{}/{}' is unavailable on backend '{}': {}",
+            bucket,
+            object,
+            s3.name(),
+            err
+        );
+
+        for name in s3.fallbacks() {
+            let fallback = match ctx.s3.get(name) {
+                Some(fallback) => fallback,
+                None => continue,
+            };
+
+            match head_object(fallback, bucket, object).await {
+                Ok(()) => {
+                    warn!(
+                        "Reading object '{}/{}' from fallback backend '{}' of '{}'",
+                        bucket,
+                        object,
+                        name,
+                        s3.name()
+                    );
+                    METRICS.inc_backend_failover(s3.name(), name);
+                    return fallback.clone();
+                }
+                Err(err) => warn!(
+                    "Object '{}/{}' is unavailable on fallback backend '{}': {}",
+                    bucket, object, name, err
+                ),
+            }
+        }
+
+        METRICS.inc_backend_failover(s3.name(), "none");
+    }
+
+    s3
+}
+
+async fn head_object(s3: &Client, bucket: &str, object: &str) -> anyhow::Result<()> {
+    match tokio::time::timeout(FAILOVER_TIMEOUT, s3.head_object(bucket, object)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(anyhow!("{}", err)),
+        Err(_) => Err(anyhow!("timed out")),
+    }
+}
+
+pub fn json_response(value: serde_json::Value) -> Response {
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        value.to_string(),
+    )
+        .into_response()
+}
+
 pub fn s3_object(set: &str, object: &str) -> String {
     format!("{set}.{object}")
 }
 
 pub fn wrap_error(kind: ErrorKind, msg: String) -> Response {
-    use anyhow::anyhow;
-
     error!("{}", msg);
     Error::new(kind, Some(anyhow!(msg))).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::AppConfig;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    fn context(primary: &str, fallback: &str) -> Arc<AppContext> {
+        let config = format!(
+            r#"
+            id = "storage.svc.example.org"
+            authn = {{}}
+            authz = {{}}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.primary]
+            endpoint = "{}"
+            region = "test"
+            credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+            fallbacks = ["fallback"]
+
+            [backend.fallback]
+            endpoint = "{}"
+            region = "test"
+            credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+
+            [audiences_settings."example.org"]
+        "#,
+            primary, fallback
+        );
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        Arc::new(AppContext::build(config, None).expect("context"))
+    }
+
+    /// An endpoint responding to each request with an empty `200 OK`.
+    fn serve_ok() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("address");
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn failover_hanging_backend() {
+        // Connections are queued by the listener but never responded to.
+        let hanging = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let primary = format!("http://{}", hanging.local_addr().expect("address"));
+        let ctx = context(&primary, &serve_ok());
+
+        let s3 = ctx.s3.get("primary").expect("primary").clone();
+        let picked = failover(&ctx, s3, "example.org", "set.object").await;
+        assert_eq!(picked.name(), "fallback");
+
+        let s3 = ctx.s3.get("fallback").expect("fallback").clone();
+        let picked = failover(&ctx, s3, "example.org", "set.object").await;
+        assert_eq!(picked.name(), "fallback");
+    }
+}
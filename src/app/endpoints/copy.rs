@@ -0,0 +1,330 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::header::{HeaderMap, HeaderValue},
+    response::Response,
+};
+use futures::future::join_all;
+use http::header::REFERER;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use svc_authn::AccountId;
+use svc_utils::extractors::AccountIdExtractor;
+
+use super::{authorize_action, json_response, resolve_set, s3_object, wrap_error};
+use crate::{
+    app::{context::AppContext, error::ErrorKind},
+    s3::ApiError,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct CopyPayload {
+    pub(super) source: ObjectLocation,
+    pub(super) target: ObjectLocation,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ObjectLocation {
+    pub(super) set: String,
+    object: String,
+}
+
+pub async fn backend_copy(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path(back): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CopyPayload>,
+) -> Response {
+    copy_ns(ctx, back, payload, sub, headers.get(REFERER), false).await
+}
+
+pub async fn backend_move(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path(back): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CopyPayload>,
+) -> Response {
+    copy_ns(ctx, back, payload, sub, headers.get(REFERER), true).await
+}
+
+async fn copy_ns(
+    ctx: Arc<AppContext>,
+    back: String,
+    body: CopyPayload,
+    sub: AccountId,
+    referer: Option<&HeaderValue>,
+    is_move: bool,
+) -> Response {
+    let op = if is_move {
+        "Error moving an object between sets"
+    } else {
+        "Error copying an object between sets"
+    };
+
+    let (s3, source_set) = match resolve_set(
+        &ctx,
+        &back,
+        &body.source.set,
+        referer,
+        ErrorKind::ObjectCopyingError,
+        op,
+    ) {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+    let (_, target_set) = match resolve_set(
+        &ctx,
+        &back,
+        &body.target.set,
+        referer,
+        ErrorKind::ObjectCopyingError,
+        op,
+    ) {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let source_bucket = source_set.bucket().to_string();
+    let source_object = s3_object(source_set.label(), &body.source.object);
+    let target_bucket = target_set.bucket().to_string();
+    let target_object = s3_object(target_set.label(), &body.target.object);
+
+    if source_bucket == target_bucket && source_object == target_object {
+        return wrap_error(
+            ErrorKind::InvalidPayload,
+            format!("{}: source and target are the same object", op),
+        );
+    }
+
+    // Moving an object removes it from the source set.
+    let mut intents = vec![
+        (&body.source.set, &source_set, "read"),
+        (&body.target.set, &target_set, "update"),
+    ];
+    if is_move {
+        intents.push((&body.source.set, &source_set, "delete"));
+    }
+    let results =
+        join_all(intents.into_iter().map(|(set, set_s, action)| {
+            authorize_action(&ctx, set, set_s, sub.clone(), action, op)
+        }))
+        .await;
+    if let Some(Err(err)) = results.into_iter().find(Result::is_err) {
+        return *err;
+    }
+
+    let etag = match s3
+        .copy_object(
+            &source_bucket,
+            &source_object,
+            &target_bucket,
+            &target_object,
+        )
+        .await
+    {
+        Ok(etag) => etag,
+        Err(ApiError::NotFound(err)) => {
+            return wrap_error(ErrorKind::ObjectNotFound, format!("{}: {}", op, err))
+        }
+        Err(ApiError::Other(err)) => {
+            return wrap_error(ErrorKind::ObjectCopyingError, format!("{}: {}", op, err))
+        }
+    };
+
+    if is_move {
+        if let Err(err) = s3.delete_object(&source_bucket, &source_object).await {
+            return wrap_error(
+                ErrorKind::ObjectPartiallyMoved,
+                format!(
+                    "{}: the object is copied to '{}/{}' (etag = {}) but not deleted from '{}/{}': {}",
+                    op,
+                    target_bucket,
+                    target_object,
+                    etag.as_deref().unwrap_or("none"),
+                    source_bucket,
+                    source_object,
+                    err
+                ),
+            );
+        }
+    }
+
+    json_response(json!({ "etag": etag }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::AppConfig;
+    use http::StatusCode;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    /// Serves S3 requests recording their methods and paths,
+    /// `DELETE` requests are answered with `delete_status`.
+    fn serve(delete_status: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("address");
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap_or_default();
+                let req = String::from_utf8_lossy(&buf[..len]);
+                let line = req.lines().next().unwrap_or_default();
+                let mut parts = line.split(' ');
+                let method = parts.next().unwrap_or_default().to_owned();
+                let path = parts.next().unwrap_or_default().to_owned();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", method, path));
+
+                let (status, body) = match method.as_str() {
+                    "HEAD" => ("200 OK", ""),
+                    "PUT" => (
+                        "200 OK",
+                        "<CopyObjectResult><ETag>\"etag\"</ETag></CopyObjectResult>",
+                    ),
+                    "DELETE" => (delete_status, ""),
+                    _ => ("400 Bad Request", ""),
+                };
+                let resp = format!(
+                    "HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    fn context(back: &str, endpoint: &str) -> Arc<AppContext> {
+        let config = format!(
+            r#"
+            id = "storage.svc.example.org"
+            authn = {{}}
+
+            [authz."example.org"]
+            type = "localwhitelist"
+            records = [
+                {{ subject_account_id = "user.usr.example.org", object = ["sets", "src.example.org::a"], action = "read" }},
+                {{ subject_account_id = "user.usr.example.org", object = ["sets", "src.example.org::a"], action = "delete" }},
+                {{ subject_account_id = "user.usr.example.org", object = ["sets", "dst.example.org::b"], action = "update" }},
+                {{ subject_account_id = "user.usr.example.org", object = ["sets", "ro.example.org::c"], action = "read" }},
+            ]
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.{}]
+
+            [audiences_settings."example.org"]
+        "#,
+            back
+        );
+        let prefix = back.to_uppercase();
+        std::env::set_var(format!("{}_AWS_ACCESS_KEY_ID", prefix), "key");
+        std::env::set_var(format!("{}_AWS_SECRET_ACCESS_KEY", prefix), "secret");
+        std::env::set_var(format!("{}_AWS_ENDPOINT", prefix), endpoint);
+        std::env::set_var(format!("{}_AWS_REGION", prefix), "test");
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        Arc::new(AppContext::build(config, None).expect("context"))
+    }
+
+    fn payload(source: &str, target: &str) -> CopyPayload {
+        CopyPayload {
+            source: ObjectLocation {
+                set: source.to_owned(),
+                object: "x".to_owned(),
+            },
+            target: ObjectLocation {
+                set: target.to_owned(),
+                object: "x".to_owned(),
+            },
+        }
+    }
+
+    async fn copy(ctx: Arc<AppContext>, back: &str, body: CopyPayload, is_move: bool) -> Response {
+        let sub = AccountId::new("user", "usr.example.org");
+        copy_ns(ctx, back.to_owned(), body, sub, None, is_move).await
+    }
+
+    #[tokio::test]
+    async fn copy_same_object() {
+        let (endpoint, requests) = serve("204 No Content");
+        let ctx = context("copysame", &endpoint);
+
+        let body = payload("src.example.org::a", "src.example.org::a");
+        let resp = copy(ctx, "copysame", body, false).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn copy_access_denied() {
+        let (endpoint, requests) = serve("204 No Content");
+        let ctx = context("copydenied", &endpoint);
+
+        // No read access to the source set.
+        let body = payload("dst.example.org::b", "src.example.org::a");
+        let resp = copy(ctx.clone(), "copydenied", body, false).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // No update access to the target set.
+        let body = payload("src.example.org::a", "ro.example.org::c");
+        let resp = copy(ctx.clone(), "copydenied", body, false).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // No delete access to the source set when moving.
+        let body = payload("ro.example.org::c", "dst.example.org::b");
+        let resp = copy(ctx.clone(), "copydenied", body, true).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn move_deletes_source() {
+        let (endpoint, requests) = serve("204 No Content");
+        let ctx = context("movedelete", &endpoint);
+
+        let body = payload("src.example.org::a", "dst.example.org::b");
+        let resp = copy(ctx, "movedelete", body, true).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].starts_with("HEAD /src.example.org/"));
+        assert!(requests[1].starts_with("PUT /dst.example.org/"));
+        assert!(requests[2].starts_with("DELETE /src.example.org/"));
+    }
+
+    #[tokio::test]
+    async fn move_partially() {
+        let (endpoint, requests) = serve("500 Internal Server Error");
+        let ctx = context("movepartial", &endpoint);
+
+        let body = payload("src.example.org::a", "dst.example.org::b");
+        let resp = copy(ctx, "movepartial", body, true).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            resp.extensions().get::<ErrorKind>(),
+            Some(&ErrorKind::ObjectPartiallyMoved)
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,332 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::header::HeaderMap,
+    response::Response,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
+use svc_utils::extractors::AccountIdExtractor;
+
+use super::{
+    backend_copy, backend_delete, backend_head, backend_list, backend_move, backend_read,
+    backend_sign, backend_sign_batch, backend_sign_post, json_response, multipart_abort,
+    multipart_complete, multipart_initiate, multipart_sign_part, wrap_error, CompletePayload,
+    CopyPayload, InitiatePayload, ListQuery, SignPartPayload, SignPayload, SignPostPayload,
+};
+use crate::app::{context::AppContext, error::ErrorKind, maxmind::LocationExtractor};
+
+const OP: &str = "Error resolving a default backend";
+
+/// Resolves `default_backend` of the audience of the set.
+fn default_backend(ctx: &AppContext, set: &str) -> Result<String, Box<Response>> {
+    let set_s = ctx.aud_estm.parse_set(set).map_err(|err| {
+        Box::new(wrap_error(
+            ErrorKind::InvalidPayload,
+            format!("{}: {}", OP, err),
+        ))
+    })?;
+    let audience = set_s.bucket().audience();
+
+    ctx.audiences_settings
+        .get(audience)
+        .and_then(|aud_settings| aud_settings.default_backend())
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| {
+            Box::new(wrap_error(
+                ErrorKind::BackendNotFound,
+                format!(
+                    "{}: Default backend of the audience '{}' is not configured",
+                    OP, audience
+                ),
+            ))
+        })
+}
+
+/// Resolves the default backend shared by all of the sets.
+fn common_default_backend<'a>(
+    ctx: &AppContext,
+    mut sets: impl Iterator<Item = &'a str>,
+) -> Result<String, Box<Response>> {
+    let back = match sets.next() {
+        Some(set) => default_backend(ctx, set)?,
+        None => {
+            return Err(Box::new(wrap_error(
+                ErrorKind::InvalidPayload,
+                format!("{}: no sets are given", OP),
+            )))
+        }
+    };
+    for set in sets {
+        if ctx.s3.get(&default_backend(ctx, set)?).map(|s3| s3.name())
+            != ctx.s3.get(&back).map(|s3| s3.name())
+        {
+            return Err(Box::new(wrap_error(
+                ErrorKind::InvalidPayload,
+                format!("{}: sets have different default backends", OP),
+            )));
+        }
+    }
+
+    Ok(back)
+}
+
+pub async fn default_read(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    location: LocationExtractor,
+    Path((set, object)): Path<(String, String)>,
+    query: Query<BTreeMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => {
+            let path = Path((back, set, object));
+            backend_read(State(ctx), sub, location, path, query, headers).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_list(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path(set): Path<String>,
+    query: Query<ListQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => backend_list(State(ctx), sub, Path((back, set)), query, headers).await,
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_head(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path((set, object)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => backend_head(State(ctx), sub, Path((back, set, object)), headers).await,
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_delete(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path((set, object)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => backend_delete(State(ctx), sub, Path((back, set, object)), headers).await,
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_sign(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    location: LocationExtractor,
+    headers: HeaderMap,
+    Json(payload): Json<SignPayload>,
+) -> Response {
+    match default_backend(&ctx, &payload.set) {
+        Ok(back) => {
+            backend_sign(
+                State(ctx),
+                sub,
+                location,
+                Path(back),
+                headers,
+                Json(payload),
+            )
+            .await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_sign_batch(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    location: LocationExtractor,
+    headers: HeaderMap,
+    Json(payload): Json<Vec<SignPayload>>,
+) -> Response {
+    if payload.is_empty() {
+        return json_response(json!([]));
+    }
+
+    match common_default_backend(&ctx, payload.iter().map(|item| item.set.as_str())) {
+        Ok(back) => {
+            let path = Path(back);
+            backend_sign_batch(State(ctx), sub, location, path, headers, Json(payload)).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_sign_post(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    location: LocationExtractor,
+    headers: HeaderMap,
+    Json(payload): Json<SignPostPayload>,
+) -> Response {
+    match default_backend(&ctx, &payload.set) {
+        Ok(back) => {
+            let path = Path(back);
+            backend_sign_post(State(ctx), sub, location, path, headers, Json(payload)).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_copy(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    headers: HeaderMap,
+    Json(payload): Json<CopyPayload>,
+) -> Response {
+    let sets = [payload.source.set.as_str(), payload.target.set.as_str()];
+    match common_default_backend(&ctx, sets.iter().copied()) {
+        Ok(back) => backend_copy(State(ctx), sub, Path(back), headers, Json(payload)).await,
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_move(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    headers: HeaderMap,
+    Json(payload): Json<CopyPayload>,
+) -> Response {
+    let sets = [payload.source.set.as_str(), payload.target.set.as_str()];
+    match common_default_backend(&ctx, sets.iter().copied()) {
+        Ok(back) => backend_move(State(ctx), sub, Path(back), headers, Json(payload)).await,
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_multipart_initiate(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path((set, object)): Path<(String, String)>,
+    headers: HeaderMap,
+    payload: Json<InitiatePayload>,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => {
+            let path = Path((back, set, object));
+            multipart_initiate(State(ctx), sub, path, headers, payload).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_multipart_sign_part(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    location: LocationExtractor,
+    Path((set, object, upload_id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    payload: Json<SignPartPayload>,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => {
+            let path = Path((back, set, object, upload_id));
+            multipart_sign_part(State(ctx), sub, location, path, headers, payload).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_multipart_complete(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path((set, object, upload_id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    payload: Json<CompletePayload>,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => {
+            let path = Path((back, set, object, upload_id));
+            multipart_complete(State(ctx), sub, path, headers, payload).await
+        }
+        Err(err) => *err,
+    }
+}
+
+pub async fn default_multipart_abort(
+    State(ctx): State<Arc<AppContext>>,
+    sub: AccountIdExtractor,
+    Path((set, object, upload_id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    match default_backend(&ctx, &set) {
+        Ok(back) => {
+            let path = Path((back, set, object, upload_id));
+            multipart_abort(State(ctx), sub, path, headers).await
+        }
+        Err(err) => *err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::AppConfig;
+    use http::StatusCode;
+
+    fn context() -> AppContext {
+        let config = r#"
+            id = "storage.svc.example.org"
+            authn = {}
+
+            [authz."example.org"]
+            type = "localwhitelist"
+            records = []
+
+            [authz."other.org"]
+            type = "localwhitelist"
+            records = []
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.primary]
+            endpoint = "http://localhost:9000"
+            region = "test"
+            credentials = { type = "static", access_key_id = "key", secret_access_key = "secret" }
+
+            [audiences_settings."example.org"]
+            default_backend = "primary"
+
+            [audiences_settings."other.org"]
+        "#;
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        AppContext::build(config, None).expect("context")
+    }
+
+    #[test]
+    fn default_backend_test() {
+        let ctx = context();
+
+        let back = default_backend(&ctx, "data.example.org::a").expect("default backend");
+        assert_eq!(back, "primary");
+
+        let err = default_backend(&ctx, "data.unknown.org::a").expect_err("invalid set");
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        let err = default_backend(&ctx, "invalid").expect_err("invalid set");
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let err = default_backend(&ctx, "data.other.org::a").expect_err("not configured");
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,49 @@
+use axum::{
+    extract::{Extension, Path, State},
+    response::Response,
+};
+use serde_json::json;
+use std::sync::Arc;
+use svc_utils::extractors::AccountIdExtractor;
+
+use super::{json_response, wrap_error};
+use crate::app::{
+    config::CountryOverrideConfig, context::AppContext, error::ErrorKind, maxmind::ClientGeo,
+};
+
+/// Reports a resolved location of the client and proxy hosts
+/// signed URLs of the backend may be rewritten to for it.
+///
+/// The topology of proxy hosts is only disclosed to accounts trusted with the country override.
+pub async fn backend_geo(
+    State(ctx): State<Arc<AppContext>>,
+    Extension(country_override): Extension<Arc<CountryOverrideConfig>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    geo: ClientGeo,
+    Path(back): Path<String>,
+) -> Response {
+    if !country_override.accounts.contains(&sub) {
+        return wrap_error(
+            ErrorKind::AccessDenied,
+            format!("Error resolving geo: account '{}' is not trusted", sub),
+        );
+    }
+
+    let s3 = match ctx.s3.get(&back) {
+        Some(val) => val.clone(),
+        None => {
+            return wrap_error(
+                ErrorKind::BackendNotFound,
+                format!("Error resolving geo: Backend '{}' is not found", &back),
+            )
+        }
+    };
+
+    json_response(json!({
+        "ip_address": geo.ip_address,
+        "location": geo.location,
+        "overridden": geo.overridden,
+        "proxy_strategy": s3.proxy_strategy(),
+        "proxy_routes": s3.proxy_routes(&geo.location),
+    }))
+}
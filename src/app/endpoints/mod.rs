@@ -4,5 +4,23 @@ pub use self::set::*;
 mod sign;
 pub use self::sign::*;
 
+mod copy;
+pub use self::copy::*;
+
+mod post;
+pub use self::post::*;
+
+mod multipart;
+pub use self::multipart::*;
+
+mod default;
+pub use self::default::*;
+
+mod geo;
+pub use self::geo::*;
+
+mod ready;
+pub use self::ready::*;
+
 mod common;
 pub use self::common::*;
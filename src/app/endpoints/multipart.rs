@@ -0,0 +1,379 @@
+use anyhow::bail;
+use axum::{
+    extract::{Json, Path, State},
+    http::header::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use http::{header::REFERER, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use svc_utils::extractors::AccountIdExtractor;
+
+use super::{authorize_set, json_response, s3_object, signature_expires_in, wrap_error};
+use crate::{
+    app::{
+        context::AppContext,
+        error::ErrorKind,
+        maxmind::LocationExtractor,
+        util::{S3SignedRequestBuilder, ACCOUNT_ID_METADATA},
+    },
+    s3::ApiError,
+};
+
+const MIN_PART_NUMBER: i64 = 1;
+const MAX_PART_NUMBER: i64 = 10000;
+
+#[derive(Debug, Deserialize)]
+pub struct InitiatePayload {
+    content_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignPartPayload {
+    part_number: i64,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    expires_in: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletePayload {
+    parts: Vec<CompletedPart>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletedPart {
+    part_number: i64,
+    etag: String,
+}
+
+pub async fn multipart_initiate(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set, object)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<InitiatePayload>,
+) -> Response {
+    let op = "Error initiating a multipart upload";
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub.clone(),
+        "update",
+        headers.get(REFERER),
+        ErrorKind::InvalidPayload,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let object = s3_object(set_s.label(), &object);
+    let metadata = std::iter::once((ACCOUNT_ID_METADATA.to_owned(), sub.to_string())).collect();
+
+    match s3
+        .create_multipart_upload(&bucket, &object, payload.content_type, metadata)
+        .await
+    {
+        Ok(upload_id) => json_response(json!({ "upload_id": upload_id })),
+        Err(err) => api_error(op, err),
+    }
+}
+
+pub async fn multipart_sign_part(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    LocationExtractor(location): LocationExtractor,
+    Path((back, set, object, upload_id)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<SignPartPayload>,
+) -> Response {
+    let op = "Error signing a part of the multipart upload";
+    if !(MIN_PART_NUMBER..=MAX_PART_NUMBER).contains(&payload.part_number) {
+        return wrap_error(
+            ErrorKind::InvalidPayload,
+            format!(
+                "{}: part number must be in range from {} to {}",
+                op, MIN_PART_NUMBER, MAX_PART_NUMBER
+            ),
+        );
+    }
+
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub.clone(),
+        "update",
+        headers.get(REFERER),
+        ErrorKind::InvalidPayload,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let expires_in = signature_expires_in(&ctx, &s3, &set_s, payload.expires_in);
+
+    let mut builder = S3SignedRequestBuilder::new()
+        .method("PUT")
+        .bucket(&set_s.bucket().to_string())
+        .object(&s3_object(set_s.label(), &object))
+        .add_param("partNumber", &payload.part_number.to_string())
+        .add_param("uploadId", &upload_id)
+        .expires_in(expires_in);
+    for (key, val) in payload.headers {
+        builder = builder.add_header(&key, &val);
+    }
+
+    match builder.build(&s3, location, &sub).await {
+        Ok(uri) => json_response(json!({ "uri": uri })),
+        Err(err) => wrap_error(ErrorKind::SigningError, format!("{}: {}", op, err)),
+    }
+}
+
+pub async fn multipart_complete(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set, object, upload_id)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<CompletePayload>,
+) -> Response {
+    let op = "Error completing a multipart upload";
+    let parts = match completed_parts(payload.parts) {
+        Ok(val) => val,
+        Err(err) => return wrap_error(ErrorKind::InvalidPayload, format!("{}: {}", op, err)),
+    };
+
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub,
+        "update",
+        headers.get(REFERER),
+        ErrorKind::InvalidPayload,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let object = s3_object(set_s.label(), &object);
+
+    match s3
+        .complete_multipart_upload(&bucket, &object, &upload_id, parts)
+        .await
+    {
+        Ok(etag) => json_response(json!({ "etag": etag })),
+        Err(err) => api_error(op, err),
+    }
+}
+
+pub async fn multipart_abort(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set, object, upload_id)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let op = "Error aborting a multipart upload";
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub,
+        "update",
+        headers.get(REFERER),
+        ErrorKind::InvalidPayload,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let object = s3_object(set_s.label(), &object);
+
+    match s3
+        .abort_multipart_upload(&bucket, &object, &upload_id)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => api_error(op, err),
+    }
+}
+
+/// Validates parts of the payload and orders them by part number as the backend requires.
+fn completed_parts(mut parts: Vec<CompletedPart>) -> anyhow::Result<Vec<(i64, String)>> {
+    if parts.is_empty() {
+        bail!("at least one part is required");
+    }
+
+    parts.sort_by_key(|part| part.part_number);
+    for (idx, part) in parts.iter().enumerate() {
+        if !(MIN_PART_NUMBER..=MAX_PART_NUMBER).contains(&part.part_number) {
+            bail!(
+                "part number must be in range from {} to {}",
+                MIN_PART_NUMBER,
+                MAX_PART_NUMBER
+            );
+        }
+        if idx > 0 && parts[idx - 1].part_number == part.part_number {
+            bail!("part number {} is duplicated", part.part_number);
+        }
+        if part.etag.is_empty() {
+            bail!("etag of part {} is empty", part.part_number);
+        }
+    }
+
+    Ok(parts
+        .into_iter()
+        .map(|part| (part.part_number, part.etag))
+        .collect())
+}
+
+fn api_error(op: &str, err: ApiError) -> Response {
+    match err {
+        ApiError::NotFound(err) => wrap_error(
+            ErrorKind::MultipartUploadNotFound,
+            format!("{}: {}", op, err),
+        ),
+        ApiError::Other(err) => {
+            wrap_error(ErrorKind::MultipartUploadError, format!("{}: {}", op, err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::config::AppConfig, s3::Location};
+    use svc_authn::AccountId;
+
+    fn context() -> Arc<AppContext> {
+        let config = r#"
+            id = "storage.svc.example.org"
+            authn = {}
+            authz = {}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.yandex]
+
+            [audiences_settings."example.org"]
+        "#;
+        std::env::set_var("YANDEX_AWS_ACCESS_KEY_ID", "key");
+        std::env::set_var("YANDEX_AWS_SECRET_ACCESS_KEY", "secret");
+        std::env::set_var("YANDEX_AWS_ENDPOINT", "http://localhost:9000");
+        std::env::set_var("YANDEX_AWS_REGION", "test");
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        Arc::new(AppContext::build(config, None).expect("context"))
+    }
+
+    fn part(part_number: i64, etag: &str) -> CompletedPart {
+        CompletedPart {
+            part_number,
+            etag: etag.to_owned(),
+        }
+    }
+
+    fn sub() -> AccountIdExtractor {
+        AccountIdExtractor(AccountId::new("user", "usr.example.org"))
+    }
+
+    #[test]
+    fn completed_parts_ordered() {
+        let parts = completed_parts(vec![part(3, "c"), part(1, "a"), part(2, "b")]).unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                (1, "a".to_owned()),
+                (2, "b".to_owned()),
+                (3, "c".to_owned())
+            ]
+        );
+
+        assert!(completed_parts(vec![]).is_err());
+        assert!(completed_parts(vec![part(1, "a"), part(1, "b")]).is_err());
+        assert!(completed_parts(vec![part(0, "a")]).is_err());
+        assert!(completed_parts(vec![part(10001, "a")]).is_err());
+        assert!(completed_parts(vec![part(1, "")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn complete_invalid_parts() {
+        let path = Path((
+            "yandex".to_owned(),
+            "foo.example.org::bar".to_owned(),
+            "baz".to_owned(),
+            "upload".to_owned(),
+        ));
+        let payload = CompletePayload {
+            parts: vec![part(1, "a"), part(1, "b")],
+        };
+
+        let resp = multipart_complete(
+            State(context()),
+            sub(),
+            path,
+            HeaderMap::new(),
+            Json(payload),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_set() {
+        let path = Path((
+            "yandex".to_owned(),
+            "invalid".to_owned(),
+            "baz".to_owned(),
+            "upload".to_owned(),
+        ));
+
+        let resp = multipart_abort(State(context()), sub(), path, HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let path = Path((
+            "yandex".to_owned(),
+            "invalid".to_owned(),
+            "baz".to_owned(),
+            "upload".to_owned(),
+        ));
+        let payload = SignPartPayload {
+            part_number: 1,
+            headers: BTreeMap::new(),
+            expires_in: None,
+        };
+        let resp = multipart_sign_part(
+            State(context()),
+            sub(),
+            LocationExtractor(Location::default()),
+            path,
+            HeaderMap::new(),
+            Json(payload),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
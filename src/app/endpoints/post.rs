@@ -0,0 +1,88 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::header::HeaderMap,
+    response::Response,
+};
+use http::header::REFERER;
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use svc_utils::extractors::AccountIdExtractor;
+
+use super::{authorize_set, json_response, s3_object, signature_expires_in, wrap_error};
+use crate::{
+    app::{
+        context::AppContext, error::ErrorKind, maxmind::LocationExtractor,
+        util::ACCOUNT_ID_METADATA,
+    },
+    s3::PostPolicyConditions,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SignPostPayload {
+    pub(super) set: String,
+    object: String,
+    content_type: Option<String>,
+    content_type_prefix: Option<String>,
+    content_length_range: Option<(u64, u64)>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    expires_in: Option<Duration>,
+}
+
+pub async fn backend_sign_post(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    LocationExtractor(location): LocationExtractor,
+    Path(back): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<SignPostPayload>,
+) -> Response {
+    let op = "Error signing a POST policy";
+    if let Some((min, max)) = payload.content_length_range {
+        if min > max {
+            return wrap_error(
+                ErrorKind::InvalidPayload,
+                format!("{}: invalid content length range", op),
+            );
+        }
+    }
+
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &payload.set,
+        sub.clone(),
+        "update",
+        headers.get(REFERER),
+        ErrorKind::SigningError,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let expires_in = signature_expires_in(&ctx, &s3, &set_s, payload.expires_in);
+    let conditions = PostPolicyConditions {
+        content_type: payload.content_type,
+        content_type_prefix: payload.content_type_prefix,
+        content_length_range: payload.content_length_range,
+        metadata: std::iter::once((ACCOUNT_ID_METADATA.to_owned(), sub.to_string())).collect(),
+    };
+
+    match s3
+        .presigned_post(
+            location,
+            &sub,
+            &set_s.bucket().to_string(),
+            &s3_object(set_s.label(), &payload.object),
+            &conditions,
+            &expires_in,
+        )
+        .await
+    {
+        Ok(post) => json_response(json!(post)),
+        Err(err) => wrap_error(ErrorKind::SigningError, format!("{}: {}", op, err)),
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use futures::future::join_all;
+use http::{header::CONTENT_TYPE, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use std::{future::Future, sync::Arc, time::Duration};
+use tracing::error;
+
+use crate::app::context::{AppContext, ShutdownFlag};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(CHECK_TIMEOUT)
+        .build()
+        .expect("failed to create readiness http client")
+});
+
+/// Checks backends, the authz cache and http authz endpoints
+/// responding with a status of each of them. Fails right away
+/// once the service is shutting down.
+///
+/// A failing backend or authz endpoint is only reported as long as others of the component
+/// are available, failing all pods on a partial outage would turn it into a total one.
+pub async fn readyz(
+    State(ctx): State<Arc<AppContext>>,
+    State(shutdown): State<ShutdownFlag>,
+) -> Response {
+    if shutdown.is_raised() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(CONTENT_TYPE, "application/json")],
+            json!({ "shutting_down": true }).to_string(),
+        )
+            .into_response();
+    }
+
+    let backends = join_all(
+        ctx.s3
+            .iter()
+            .map(|(back, s3)| async move { (back.to_owned(), check(s3.check(&HTTP)).await) }),
+    );
+    let authz =
+        join_all(
+            ctx.authz_endpoints
+                .iter()
+                .map(|(audience, uri)| async move {
+                    (audience.to_owned(), check(check_authz(uri)).await)
+                }),
+        );
+    let cache = async {
+        match ctx.cache {
+            Some(ref cache) => {
+                let pool = cache.pool.clone();
+                check(async move {
+                    tokio::task::spawn_blocking(move || pool.get().map(|_| ()))
+                        .await
+                        .map_err(|err| anyhow!("cache check failed: {}", err))
+                        .and_then(|result| result.context("cache is unavailable"))
+                })
+                .await
+            }
+            None => json!({ "status": "disabled" }),
+        }
+    };
+    let (backends, authz, cache) = futures::join!(backends, authz, cache);
+
+    let ready = is_ready(&[&backends, &authz], &cache);
+    let body = json!({
+        "backends": backends.into_iter().collect::<Map<_, _>>(),
+        "authz": authz.into_iter().collect::<Map<_, _>>(),
+        "cache": cache,
+    });
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        error!("service is not ready: {}", body);
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        [(CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+async fn check_authz(uri: &str) -> Result<()> {
+    let status = HTTP
+        .head(uri)
+        .send()
+        .await
+        .context("authz is unreachable")?
+        .status();
+    if status.is_server_error() {
+        return Err(anyhow!("authz responded with {}", status));
+    }
+
+    Ok(())
+}
+
+async fn check(fut: impl Future<Output = Result<()>>) -> Value {
+    let result = tokio::time::timeout(CHECK_TIMEOUT, fut)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("check timed out")));
+    status(result)
+}
+
+fn status(result: Result<()>) -> Value {
+    match result {
+        Ok(()) => json!({ "status": "ok" }),
+        Err(err) => json!({ "status": "error", "error": format!("{:#}", err) }),
+    }
+}
+
+/// The service isn't ready once the cache or every check of a component fails.
+fn is_ready(components: &[&[(String, Value)]], cache: &Value) -> bool {
+    is_ok(cache)
+        && components
+            .iter()
+            .all(|results| results.is_empty() || results.iter().any(|(_, value)| is_ok(value)))
+}
+
+fn is_ok(value: &Value) -> bool {
+    value["status"] != "error"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::AppConfig;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    fn ok() -> Value {
+        status(Ok(()))
+    }
+
+    fn failed() -> Value {
+        status(Err(anyhow!("failed")))
+    }
+
+    fn results(values: &[Value]) -> Vec<(String, Value)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| (idx.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn is_ready_test() {
+        let disabled = json!({ "status": "disabled" });
+
+        assert!(is_ready(&[&[], &[]], &disabled));
+        assert!(is_ready(&[&results(&[ok(), ok()]), &[]], &ok()));
+        // A partial outage of a component keeps the service ready.
+        assert!(is_ready(
+            &[&results(&[ok(), failed()]), &results(&[failed(), ok()])],
+            &ok()
+        ));
+
+        assert!(!is_ready(
+            &[&results(&[failed(), failed()]), &results(&[ok()])],
+            &ok()
+        ));
+        assert!(!is_ready(
+            &[&results(&[ok()]), &results(&[failed()])],
+            &disabled
+        ));
+        assert!(!is_ready(
+            &[&results(&[ok()]), &results(&[ok()])],
+            &failed()
+        ));
+    }
+
+    /// An endpoint responding to each request with an empty `200 OK`.
+    fn serve_ok() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("address");
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// An endpoint refusing connections.
+    fn unreachable() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        format!("http://{}", listener.local_addr().expect("address"))
+    }
+
+    fn context(endpoints: &[String]) -> Arc<AppContext> {
+        let backends = endpoints
+            .iter()
+            .enumerate()
+            .map(|(idx, endpoint)| {
+                format!(
+                    r#"
+                    [backend.backend{}]
+                    endpoint = "{}"
+                    region = "test"
+                    credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+                    "#,
+                    idx, endpoint
+                )
+            })
+            .collect::<String>();
+        let config = format!(
+            r#"
+            id = "storage.svc.example.org"
+            authn = {{}}
+            authz = {{}}
+            audiences_settings = {{}}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            {}
+            "#,
+            backends
+        );
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        Arc::new(AppContext::build(config, None).expect("context"))
+    }
+
+    #[tokio::test]
+    async fn readyz_backends() {
+        let shutdown = ShutdownFlag::default();
+
+        let ctx = context(&[serve_ok(), unreachable()]);
+        let resp = readyz(State(ctx), State(shutdown.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let ctx = context(&[unreachable(), unreachable()]);
+        let resp = readyz(State(ctx), State(shutdown)).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn readyz_shutting_down() {
+        let shutdown = ShutdownFlag::default();
+        shutdown.raise();
+
+        let resp = readyz(State(context(&[serve_ok()])), State(shutdown)).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
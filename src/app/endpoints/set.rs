@@ -1,36 +1,102 @@
 use axum::{
-    extract::{Path, State},
-    http::header::{HeaderMap, HeaderValue},
+    body::{Bytes, StreamBody},
+    extract::{Path, Query, State},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
     response::{IntoResponse, Response},
 };
-use http::{header::REFERER, StatusCode};
-use std::sync::Arc;
+use http::{
+    header::{
+        ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LANGUAGE,
+        CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, EXPIRES, IF_MATCH, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE, REFERER,
+    },
+    StatusCode,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
 use svc_authn::AccountId;
 use svc_utils::extractors::AccountIdExtractor;
+use tracing::error;
 
-use super::{s3_object, valid_referer, wrap_error};
-use crate::app::{
-    authz::AuthzObject, context::AppContext, error::ErrorKind, maxmind::CountryExtractor,
+use super::{
+    authorize_set, failover, json_response, read_mode, s3_object, valid_referer, wrap_error,
+};
+use crate::{
+    app::{
+        authz::AuthzObject,
+        config::ReadMode,
+        context::AppContext,
+        error::ErrorKind,
+        maxmind::LocationExtractor,
+        util::{ResponseOverrides, S3SignedRequestBuilder},
+    },
+    s3::{ApiError, Client, Location},
 };
 
+const MAX_KEYS: i64 = 1000;
+
+/// Headers of a read request passed to the backend when an object is streamed.
+const STREAM_REQUEST_HEADERS: [HeaderName; 5] = [
+    RANGE,
+    IF_MATCH,
+    IF_NONE_MATCH,
+    IF_MODIFIED_SINCE,
+    IF_UNMODIFIED_SINCE,
+];
+
+/// Headers of a backend response passed to the client when an object is streamed.
+const STREAM_RESPONSE_HEADERS: [HeaderName; 11] = [
+    ACCEPT_RANGES,
+    CACHE_CONTROL,
+    CONTENT_DISPOSITION,
+    CONTENT_ENCODING,
+    CONTENT_LANGUAGE,
+    CONTENT_LENGTH,
+    CONTENT_RANGE,
+    CONTENT_TYPE,
+    ETAG,
+    EXPIRES,
+    LAST_MODIFIED,
+];
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    continuation_token: Option<String>,
+    max_keys: Option<i64>,
+}
+
 pub async fn backend_read(
     State(ctx): State<Arc<AppContext>>,
     AccountIdExtractor(sub): AccountIdExtractor,
-    CountryExtractor(country): CountryExtractor,
+    LocationExtractor(location): LocationExtractor,
     Path((back, set, object)): Path<(String, String, String)>,
+    Query(query): Query<BTreeMap<String, String>>,
     headers: HeaderMap,
 ) -> Response {
-    read_ns(ctx, country, back, set, object, sub, headers.get(REFERER)).await
+    let overrides = match ResponseOverrides::from_query(query) {
+        Ok(val) => val,
+        Err(err) => {
+            return wrap_error(
+                ErrorKind::InvalidPayload,
+                format!("Error reading an object by set: {}", err),
+            )
+        }
+    };
+
+    read_ns(ctx, location, back, set, object, sub, overrides, &headers).await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn read_ns(
     ctx: Arc<AppContext>,
-    country: Option<String>,
+    location: Location,
     back: String,
     set: String,
     object: String,
     sub: AccountId,
-    referer: Option<&HeaderValue>,
+    overrides: ResponseOverrides,
+    headers: &HeaderMap,
 ) -> Response {
     let zobj = AuthzObject::new(&["sets", &set]);
     let zact = "read";
@@ -49,15 +115,15 @@ async fn read_ns(
 
     match ctx.aud_estm.parse_set(&set) {
         Ok(set_s) => {
+            let referer = headers.get(REFERER);
             if let Err(err) = valid_referer(&ctx, &set_s.bucket().to_string(), referer) {
                 return err;
             }
 
             match ctx
-                .authz
                 .authorize(
                     set_s.bucket().audience().to_string(),
-                    sub,
+                    sub.clone(),
                     Box::new(zobj),
                     zact.to_string(),
                 )
@@ -70,8 +136,20 @@ async fn read_ns(
                 Ok(_) => {
                     let bucket = set_s.bucket().to_string();
                     let object = s3_object(set_s.label(), &object);
+                    let s3 = failover(&ctx, s3, &bucket, &object).await;
 
-                    match s3.presigned_url(country, "GET", &bucket, &object) {
+                    if read_mode(&ctx, &s3, &set_s) == ReadMode::Stream {
+                        return stream(&s3, &bucket, &object, &overrides, headers).await;
+                    }
+
+                    match S3SignedRequestBuilder::new()
+                        .method("GET")
+                        .bucket(&bucket)
+                        .object(&object)
+                        .response_overrides(&overrides)
+                        .build(&s3, location, &sub)
+                        .await
+                    {
                         Ok(uri) => redirect(uri),
                         Err(err) => wrap_error(
                             ErrorKind::ObjectReadingError,
@@ -88,6 +166,208 @@ async fn read_ns(
     }
 }
 
+pub async fn backend_list(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set)): Path<(String, String)>,
+    Query(query): Query<ListQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let op = "Error listing objects in the set";
+    let max_keys = query.max_keys.unwrap_or(MAX_KEYS);
+    if !(1..=MAX_KEYS).contains(&max_keys) {
+        return wrap_error(
+            ErrorKind::InvalidPayload,
+            format!("{}: max_keys must be in range from 1 to {}", op, MAX_KEYS),
+        );
+    }
+
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub,
+        "list",
+        headers.get(REFERER),
+        ErrorKind::ObjectListingError,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let prefix = s3_object(set_s.label(), "");
+
+    match s3
+        .list_objects(&bucket, &prefix, query.continuation_token, Some(max_keys))
+        .await
+    {
+        Ok(list) => json_response(json!(list)),
+        Err(ApiError::NotFound(err)) => {
+            wrap_error(ErrorKind::BucketNotFound, format!("{}: {}", op, err))
+        }
+        Err(ApiError::Other(err)) => {
+            wrap_error(ErrorKind::ObjectListingError, format!("{}: {}", op, err))
+        }
+    }
+}
+
+pub async fn backend_head(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set, object)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let op = "Error reading metadata of an object by set";
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub,
+        "read",
+        headers.get(REFERER),
+        ErrorKind::ObjectMetadataError,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let object = s3_object(set_s.label(), &object);
+
+    match s3.head_object(&bucket, &object).await {
+        Ok(metadata) => {
+            let mut resp_headers = HeaderMap::new();
+            let values = [
+                (CONTENT_LENGTH, metadata.size.map(|size| size.to_string())),
+                (CONTENT_TYPE, metadata.content_type),
+                (ETAG, metadata.etag),
+                (LAST_MODIFIED, metadata.last_modified),
+            ];
+            for (name, value) in values {
+                if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                    resp_headers.insert(name, value);
+                }
+            }
+
+            (StatusCode::OK, resp_headers).into_response()
+        }
+        Err(ApiError::NotFound(err)) => {
+            wrap_error(ErrorKind::ObjectNotFound, format!("{}: {}", op, err))
+        }
+        Err(ApiError::Other(err)) => {
+            wrap_error(ErrorKind::ObjectMetadataError, format!("{}: {}", op, err))
+        }
+    }
+}
+
+pub async fn backend_delete(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    Path((back, set, object)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let op = "Error deleting an object by set";
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &set,
+        sub,
+        "delete",
+        headers.get(REFERER),
+        ErrorKind::ObjectDeletingError,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    let bucket = set_s.bucket().to_string();
+    let object = s3_object(set_s.label(), &object);
+
+    match s3.delete_object(&bucket, &object).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => wrap_error(ErrorKind::ObjectDeletingError, format!("{}: {}", op, err)),
+    }
+}
+
+/// Streams an object of the backend passing conditional and range
+/// headers to it and its status and content headers back.
+async fn stream(
+    s3: &Client,
+    bucket: &str,
+    object: &str,
+    overrides: &ResponseOverrides,
+    headers: &HeaderMap,
+) -> Response {
+    let mut req_headers = HeaderMap::new();
+    for name in STREAM_REQUEST_HEADERS {
+        if let Some(value) = headers.get(&name) {
+            req_headers.insert(name, value.clone());
+        }
+    }
+
+    let resp = match s3.get_object(bucket, object, overrides, req_headers).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            return wrap_error(
+                ErrorKind::ObjectReadingError,
+                format!("Error streaming an object by set: {:#}", err),
+            )
+        }
+    };
+
+    let status = resp.status();
+    if status == StatusCode::NOT_FOUND {
+        return wrap_error(
+            ErrorKind::ObjectNotFound,
+            format!("Error streaming an object by set: {}", object),
+        );
+    }
+    if status.is_server_error() || status == StatusCode::FORBIDDEN {
+        return wrap_error(
+            ErrorKind::ObjectReadingError,
+            format!(
+                "Error streaming an object by set: backend responded with {}",
+                status
+            ),
+        );
+    }
+    // Other statuses such as `304 Not Modified`, `412 Precondition Failed` or
+    // `416 Range Not Satisfiable` answer conditional and range headers of the client
+    // and are passed through as is.
+
+    let mut resp_headers = HeaderMap::new();
+    for name in STREAM_RESPONSE_HEADERS {
+        if let Some(value) = resp.headers().get(&name) {
+            resp_headers.insert(name, value.clone());
+        }
+    }
+
+    // The stream ends after the first error.
+    let body = futures::stream::unfold(Some(resp), |resp| async move {
+        let mut resp = resp?;
+        match resp.chunk().await {
+            Ok(Some(chunk)) => Some((Ok::<Bytes, reqwest::Error>(chunk), Some(resp))),
+            Ok(None) => None,
+            Err(err) => {
+                error!("Error streaming an object by set: {}", err);
+                Some((Err(err), None))
+            }
+        }
+    });
+
+    (status, resp_headers, StreamBody::new(body)).into_response()
+}
+
 fn redirect(uri: String) -> Response {
     (
         StatusCode::SEE_OTHER,
@@ -95,3 +375,219 @@ fn redirect(uri: String) -> Response {
     )
         .into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{config::AppConfig, error::ErrorKind};
+    use axum::body::HttpBody;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    fn context() -> Arc<AppContext> {
+        let config = r#"
+            id = "storage.svc.example.org"
+            authn = {}
+            authz = {}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.yandex]
+
+            [audiences_settings."example.org"]
+        "#;
+        std::env::set_var("YANDEX_AWS_ACCESS_KEY_ID", "key");
+        std::env::set_var("YANDEX_AWS_SECRET_ACCESS_KEY", "secret");
+        std::env::set_var("YANDEX_AWS_ENDPOINT", "http://localhost:9000");
+        std::env::set_var("YANDEX_AWS_REGION", "test");
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        Arc::new(AppContext::build(config, None).expect("context"))
+    }
+
+    async fn list(max_keys: Option<i64>) -> StatusCode {
+        let path = Path(("unknown".to_owned(), "foo.example.org::bar".to_owned()));
+        let query = ListQuery {
+            continuation_token: None,
+            max_keys,
+        };
+        let sub = AccountIdExtractor(AccountId::new("user", "usr.example.org"));
+
+        backend_list(State(context()), sub, path, Query(query), HeaderMap::new())
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn list_max_keys_bounds() {
+        assert_eq!(list(Some(0)).await, StatusCode::BAD_REQUEST);
+        assert_eq!(list(Some(-1)).await, StatusCode::BAD_REQUEST);
+        assert_eq!(list(Some(MAX_KEYS + 1)).await, StatusCode::BAD_REQUEST);
+
+        // Valid values pass through to the backend lookup.
+        assert_eq!(list(None).await, StatusCode::NOT_FOUND);
+        assert_eq!(list(Some(1)).await, StatusCode::NOT_FOUND);
+        assert_eq!(list(Some(MAX_KEYS)).await, StatusCode::NOT_FOUND);
+    }
+
+    /// A backend responding with a status of the last segment of the path
+    /// if it's numeric or with `206 Partial Content` otherwise,
+    /// it records headers of requests.
+    fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("address");
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap_or_default();
+                let req = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+                recorded.lock().unwrap().push(req.clone());
+
+                let path = req.split(' ').nth(1).unwrap_or_default();
+                let path = path.split('?').next().unwrap_or_default();
+                let resp = match path.rsplit('/').next().unwrap_or_default().parse::<u16>() {
+                    Ok(status) => format!(
+                        "HTTP/1.1 {} Status\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+                        status
+                    ),
+                    Err(_) => "HTTP/1.1 206 Partial Content\r\nconnection: close\r\n\
+                        content-length: 4\r\ncontent-range: bytes 0-3/10\r\n\
+                        etag: \"etag\"\r\nx-amz-request-id: id\r\n\r\ndata"
+                        .to_owned(),
+                };
+                let _ = stream.write_all(resp.as_bytes());
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    fn client(endpoint: &str) -> Arc<Client> {
+        let config = format!(
+            r#"
+            id = "storage.svc.example.org"
+            authn = {{}}
+            authz = {{}}
+            audiences_settings = {{}}
+
+            [http]
+            listener_address = "0.0.0.0:8080"
+
+            [backend.stream]
+            endpoint = "{}"
+            region = "test"
+            credentials = {{ type = "static", access_key_id = "key", secret_access_key = "secret" }}
+            "#,
+            endpoint
+        );
+
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&config, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize::<AppConfig>())
+            .expect("config");
+        let ctx = AppContext::build(config, None).expect("context");
+        ctx.s3.get("stream").expect("backend").clone()
+    }
+
+    #[tokio::test]
+    async fn stream_forwards_headers() {
+        let (endpoint, requests) = serve();
+        let s3 = client(&endpoint);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=0-3"));
+        headers.insert(IF_MATCH, HeaderValue::from_static("\"etag\""));
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        headers.insert(
+            IF_UNMODIFIED_SINCE,
+            HeaderValue::from_static("Thu, 22 Oct 2015 07:28:00 GMT"),
+        );
+        headers.insert("cookie", HeaderValue::from_static("session=secret"));
+
+        let resp = stream(&s3, "bucket", "object", &Default::default(), &headers).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[CONTENT_RANGE], "bytes 0-3/10");
+        assert_eq!(resp.headers()[ETAG], "\"etag\"");
+        assert!(resp.headers().get("x-amz-request-id").is_none());
+
+        let mut body = resp.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.expect("body"));
+        }
+        assert_eq!(data, b"data");
+
+        let requests = requests.lock().unwrap();
+        let req = &requests[0];
+        assert!(req.contains("range: bytes=0-3\r\n"));
+        assert!(req.contains("if-match: \"etag\"\r\n"));
+        assert!(req.contains("if-none-match: \"other\"\r\n"));
+        assert!(req.contains("if-modified-since: wed, 21 oct 2015 07:28:00 gmt\r\n"));
+        assert!(req.contains("if-unmodified-since: thu, 22 oct 2015 07:28:00 gmt\r\n"));
+        assert!(!req.contains("cookie"));
+    }
+
+    #[tokio::test]
+    async fn stream_maps_statuses() {
+        let (endpoint, _) = serve();
+        let s3 = client(&endpoint);
+
+        let cases = [
+            (
+                "404",
+                StatusCode::NOT_FOUND,
+                Some(ErrorKind::ObjectNotFound),
+            ),
+            (
+                "403",
+                StatusCode::FORBIDDEN,
+                Some(ErrorKind::ObjectReadingError),
+            ),
+            (
+                "500",
+                StatusCode::FORBIDDEN,
+                Some(ErrorKind::ObjectReadingError),
+            ),
+            (
+                "503",
+                StatusCode::FORBIDDEN,
+                Some(ErrorKind::ObjectReadingError),
+            ),
+            // Answers to conditional and range headers are passed through.
+            ("304", StatusCode::NOT_MODIFIED, None),
+            ("412", StatusCode::PRECONDITION_FAILED, None),
+            ("416", StatusCode::RANGE_NOT_SATISFIABLE, None),
+        ];
+        for (object, status, kind) in cases {
+            let resp = stream(
+                &s3,
+                "bucket",
+                object,
+                &Default::default(),
+                &HeaderMap::new(),
+            )
+            .await;
+            assert_eq!(resp.status(), status, "object = {}", object);
+            assert_eq!(
+                resp.extensions().get::<ErrorKind>().copied(),
+                kind,
+                "object = {}",
+                object
+            );
+        }
+    }
+}
@@ -4,124 +4,319 @@ use axum::{
     http::header::{HeaderMap, HeaderValue},
     response::{IntoResponse, Response},
 };
+use futures::future::join_all;
 use http::{
     header::{CONTENT_TYPE, REFERER},
     StatusCode,
 };
 use serde::Deserialize;
 use serde_json::json;
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 use svc_authn::AccountId;
 use svc_utils::extractors::AccountIdExtractor;
 
-use super::{s3_object, valid_referer, wrap_error};
-use crate::app::{
-    authz::AuthzObject, context::AppContext, error::ErrorKind, maxmind::CountryExtractor,
-    util::S3SignedRequestBuilder,
+use tracing::error;
+
+use super::{
+    authorize_set, check_referer, json_response, s3_object, signature_expires_in, wrap_error,
+};
+use crate::{
+    app::{
+        authz::AuthzObject,
+        context::AppContext,
+        error::{Error, ErrorKind, ErrorKindExt},
+        maxmind::LocationExtractor,
+        util::{ObjectAttributes, ResponseOverrides, S3SignedRequestBuilder, Set},
+    },
+    s3::{Client, Location},
 };
 
+const MAX_BATCH_SIZE: usize = 100;
+
 #[derive(Debug, Deserialize)]
 pub struct SignPayload {
-    set: String,
+    pub(super) set: String,
     object: String,
     method: String,
     headers: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "crate::serde::optional_duration")]
+    expires_in: Option<Duration>,
+    #[serde(default)]
+    response_overrides: BTreeMap<String, String>,
+    /// `x-amz-meta-*` metadata of an uploaded object without the prefix.
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
 }
 
 pub async fn backend_sign(
     State(ctx): State<Arc<AppContext>>,
     AccountIdExtractor(sub): AccountIdExtractor,
-    CountryExtractor(country): CountryExtractor,
+    LocationExtractor(location): LocationExtractor,
     Path(back): Path<String>,
     headers: HeaderMap,
     Json(payload): Json<SignPayload>,
 ) -> Response {
-    sign_ns(ctx, country, back, payload, sub, headers.get(REFERER)).await
+    sign_ns(ctx, location, back, payload, sub, headers.get(REFERER)).await
 }
 
 async fn sign_ns(
     ctx: Arc<AppContext>,
-    country: Option<String>,
+    location: Location,
     back: String,
     body: SignPayload,
     sub: AccountId,
     referer: Option<&HeaderValue>,
 ) -> Response {
-    if let Ok(set_s) = ctx.aud_estm.parse_set(&body.set) {
-        if let Err(err) = valid_referer(&ctx, &set_s.bucket().to_string(), referer) {
-            return err;
-        }
-    }
-
-    let zobj = AuthzObject::new(&["sets", &body.set]);
+    let op = "Error signing a request";
     let zact = match parse_action(&body.method) {
         Ok(val) => val,
-        Err(err) => {
-            return wrap_error(
-                ErrorKind::SigningForbidden,
-                format!("Error signing a request: {}", err),
-            )
-        }
+        Err(err) => return wrap_error(ErrorKind::SigningForbidden, format!("{}: {}", op, err)),
     };
 
+    let overrides = match response_overrides(&body) {
+        Ok(val) => val,
+        Err(err) => return wrap_error(ErrorKind::InvalidPayload, format!("{}: {}", op, err)),
+    };
+
+    let attributes = match object_attributes(&body, &sub) {
+        Ok(val) => val,
+        Err(err) => return wrap_error(ErrorKind::InvalidPayload, format!("{}: {}", op, err)),
+    };
+
+    let (s3, set_s) = match authorize_set(
+        &ctx,
+        &back,
+        &body.set,
+        sub.clone(),
+        zact,
+        referer,
+        ErrorKind::SigningError,
+        op,
+    )
+    .await
+    {
+        Ok(val) => val,
+        Err(err) => return *err,
+    };
+
+    match request_builder(&ctx, &s3, &set_s, body, &overrides, &attributes)
+        .build(&s3, location, &sub)
+        .await
+    {
+        Ok(uri) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, "application/json")],
+            signed_uri(&s3, uri, &attributes).to_string(),
+        )
+            .into_response(),
+        Err(err) => wrap_error(ErrorKind::SigningError, format!("{}: {}", op, err)),
+    }
+}
+
+pub async fn backend_sign_batch(
+    State(ctx): State<Arc<AppContext>>,
+    AccountIdExtractor(sub): AccountIdExtractor,
+    LocationExtractor(location): LocationExtractor,
+    Path(back): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<Vec<SignPayload>>,
+) -> Response {
+    sign_batch_ns(ctx, location, back, payload, sub, headers.get(REFERER)).await
+}
+
+async fn sign_batch_ns(
+    ctx: Arc<AppContext>,
+    location: Location,
+    back: String,
+    body: Vec<SignPayload>,
+    sub: AccountId,
+    referer: Option<&HeaderValue>,
+) -> Response {
+    if body.len() > MAX_BATCH_SIZE {
+        return wrap_error(
+            ErrorKind::InvalidPayload,
+            format!(
+                "Error signing a batch of requests: batch size must not exceed {}",
+                MAX_BATCH_SIZE
+            ),
+        );
+    }
+
     let s3 = match ctx.s3.get(&back) {
         Some(val) => val.clone(),
         None => {
             return wrap_error(
                 ErrorKind::BackendNotFound,
-                format!("Error signing a request: Backend '{}' is not found", &back),
+                format!(
+                    "Error signing a batch of requests: Backend '{}' is not found",
+                    &back
+                ),
             )
         }
     };
 
-    match ctx.aud_estm.parse_set(&body.set) {
-        Ok(set_s) => {
-            match ctx
-                .authz
-                .authorize(
-                    set_s.bucket().audience().to_string(),
-                    sub,
-                    Box::new(zobj),
-                    zact.to_string(),
-                )
-                .await
-            {
-                Err(err) => wrap_error(
-                    ErrorKind::SigningError,
-                    format!("Error signing a request: {}", err),
-                ),
-                Ok(_) => {
-                    // URI builder
-                    let mut builder = S3SignedRequestBuilder::new()
-                        .method(&body.method)
-                        .bucket(&set_s.bucket().to_string())
-                        .object(&s3_object(set_s.label(), &body.object));
-                    for (key, val) in body.headers {
-                        builder = builder.add_header(&key, &val);
-                    }
-                    match builder.build(&s3, country) {
-                        Ok(uri) => (
-                            StatusCode::OK,
-                            [(CONTENT_TYPE, "application/json")],
-                            json!({
-                                "uri": uri,
-                            })
-                            .to_string(),
-                        )
-                            .into_response(),
-                        Err(err) => wrap_error(
-                            ErrorKind::SigningError,
-                            format!("Error signing a request: {}", err),
-                        ),
-                    }
-                }
+    // Authorize each distinct pair of a set and an action only once.
+    let mut keys = Vec::new();
+    for item in &body {
+        if let Ok(zact) = parse_action(&item.method) {
+            let key = (item.set.clone(), zact.to_owned());
+            if !keys.contains(&key) {
+                keys.push(key);
             }
         }
-        Err(err) => wrap_error(
-            ErrorKind::SigningError,
-            format!("Error signing a request: {}", err),
-        ),
     }
+    let results = join_all(
+        keys.iter()
+            .map(|(set, zact)| authorize_intent(&ctx, set, zact, sub.clone(), referer)),
+    )
+    .await;
+    let intents: HashMap<(String, String), Result<Set, Error>> =
+        keys.into_iter().zip(results).collect();
+
+    let mut items = Vec::with_capacity(body.len());
+    for item in body {
+        let set_s = match parse_action(&item.method) {
+            Ok(zact) => intents
+                .get(&(item.set.clone(), zact.to_owned()))
+                .expect("intent must be authorized")
+                .as_ref()
+                .map_err(Clone::clone),
+            Err(err) => {
+                Err(anyhow!("Error signing a request: {}", err).kind(ErrorKind::SigningForbidden))
+            }
+        };
+
+        let overrides = response_overrides(&item).map_err(|err| {
+            anyhow!("Error signing a request: {}", err).kind(ErrorKind::InvalidPayload)
+        });
+        let attributes = object_attributes(&item, &sub).map_err(|err| {
+            anyhow!("Error signing a request: {}", err).kind(ErrorKind::InvalidPayload)
+        });
+        let uri = match (set_s, overrides, attributes) {
+            (Ok(set_s), Ok(overrides), Ok(attributes)) => {
+                request_builder(&ctx, &s3, set_s, item, &overrides, &attributes)
+                    .build(&s3, location.clone(), &sub)
+                    .await
+                    .map(|uri| (uri, attributes))
+                    .map_err(|err| {
+                        anyhow!("Error signing a request: {}", err).kind(ErrorKind::SigningError)
+                    })
+            }
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => Err(err),
+        };
+
+        items.push(match uri {
+            Ok((uri, attributes)) => signed_uri(&s3, uri, &attributes),
+            Err(err) => {
+                error!("{}", err.detail());
+                json!({ "error": err.to_svc_error() })
+            }
+        });
+    }
+
+    json_response(json!(items))
+}
+
+async fn authorize_intent(
+    ctx: &Arc<AppContext>,
+    set: &str,
+    zact: &str,
+    sub: AccountId,
+    referer: Option<&HeaderValue>,
+) -> Result<Set, Error> {
+    let set_s = ctx
+        .aud_estm
+        .parse_set(set)
+        .map_err(|err| anyhow!("Error signing a request: {}", err).kind(ErrorKind::SigningError))?;
+
+    check_referer(ctx, &set_s.bucket().to_string(), referer)?;
+
+    let zobj = AuthzObject::new(&["sets", set]);
+    ctx.authorize(
+        set_s.bucket().audience().to_string(),
+        sub,
+        Box::new(zobj),
+        zact.to_string(),
+    )
+    .await
+    .map_err(|err| anyhow!("Error signing a request: {}", err).kind(ErrorKind::SigningError))?;
+
+    Ok(set_s)
+}
+
+fn request_builder(
+    ctx: &Arc<AppContext>,
+    s3: &Client,
+    set_s: &Set,
+    body: SignPayload,
+    overrides: &ResponseOverrides,
+    attributes: &ObjectAttributes,
+) -> S3SignedRequestBuilder {
+    let expires_in = signature_expires_in(ctx, s3, set_s, body.expires_in);
+
+    let mut builder = S3SignedRequestBuilder::new()
+        .method(&body.method)
+        .bucket(&set_s.bucket().to_string())
+        .object(&s3_object(set_s.label(), &body.object))
+        .response_overrides(overrides)
+        .expires_in(expires_in);
+    for (key, val) in body.headers {
+        builder = builder.add_header(&key, &val);
+    }
+    builder.object_attributes(attributes)
+}
+
+/// A signed URI along with headers bound to the signature the client has to send.
+fn signed_uri(s3: &Client, uri: String, attributes: &ObjectAttributes) -> serde_json::Value {
+    let headers = attributes
+        .iter()
+        .filter(|(key, _)| s3.required_header(key))
+        .collect::<BTreeMap<_, _>>();
+    if headers.is_empty() {
+        return json!({ "uri": uri });
+    }
+
+    json!({ "uri": uri, "headers": headers })
+}
+
+/// Response overrides of the payload, they're only applicable to reads.
+fn response_overrides(body: &SignPayload) -> anyhow::Result<ResponseOverrides> {
+    if !body.response_overrides.is_empty() && parse_action(&body.method).ok() != Some("read") {
+        return Err(anyhow!(
+            "response overrides are only allowed for GET and HEAD methods"
+        ));
+    }
+
+    ResponseOverrides::new(body.response_overrides.clone())
+}
+
+/// Metadata and tags of the payload along with the account of the uploader,
+/// they're only applicable to uploads.
+fn object_attributes(body: &SignPayload, sub: &AccountId) -> anyhow::Result<ObjectAttributes> {
+    for key in body.headers.keys() {
+        let key = key.to_lowercase();
+        if key.starts_with("x-amz-meta-") || key == "x-amz-tagging" {
+            return Err(anyhow!(
+                "header '{}' is not allowed, use metadata and tags instead",
+                key
+            ));
+        }
+    }
+
+    if body.method != "PUT" {
+        if !body.metadata.is_empty() || !body.tags.is_empty() {
+            return Err(anyhow!("metadata and tags are only allowed for PUT method"));
+        }
+        return Ok(ObjectAttributes::default());
+    }
+
+    ObjectAttributes::new(&body.metadata, &body.tags, sub)
 }
 
 pub fn parse_action(method: &str) -> anyhow::Result<&str> {
@@ -133,3 +328,41 @@ pub fn parse_action(method: &str) -> anyhow::Result<&str> {
         _ => Err(anyhow!("invalid method = {}", method)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(headers: &[(&str, &str)]) -> SignPayload {
+        SignPayload {
+            set: "data.example.org::foo".to_owned(),
+            object: "bar".to_owned(),
+            method: "PUT".to_owned(),
+            headers: headers
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect(),
+            expires_in: None,
+            response_overrides: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn object_attributes_headers() {
+        let sub = AccountId::new("user", "usr.example.org");
+
+        let attributes = object_attributes(&payload(&[("content-type", "text/plain")]), &sub)
+            .expect("attributes");
+        assert_eq!(
+            attributes.iter().collect::<Vec<_>>(),
+            vec![("x-amz-meta-account-id", "user.usr.example.org")]
+        );
+
+        let spoofed = payload(&[("X-Amz-Meta-Account-Id", "admin.usr.example.org")]);
+        assert!(object_attributes(&spoofed, &sub).is_err());
+        let tagging = payload(&[("X-Amz-Tagging", "retention=forever")]);
+        assert!(object_attributes(&tagging, &sub).is_err());
+    }
+}
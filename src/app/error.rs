@@ -20,6 +20,17 @@ pub enum ErrorKind {
     SigningError,
     BackendNotFound,
     SigningForbidden,
+    AccessDenied,
+    InvalidPayload,
+    MultipartUploadError,
+    MultipartUploadNotFound,
+    ObjectListingError,
+    BucketNotFound,
+    ObjectNotFound,
+    ObjectMetadataError,
+    ObjectDeletingError,
+    ObjectCopyingError,
+    ObjectPartiallyMoved,
 }
 
 impl ErrorKind {
@@ -27,6 +38,11 @@ impl ErrorKind {
         let properties: ErrorKindProperties = self.into();
         properties.status
     }
+
+    pub fn kind(self) -> &'static str {
+        let properties: ErrorKindProperties = self.into();
+        properties.kind
+    }
 }
 
 impl fmt::Display for ErrorKind {
@@ -75,10 +91,66 @@ impl From<ErrorKind> for ErrorKindProperties {
                 kind: "signing_forbidden",
                 title: "Access denied",
             },
+            ErrorKind::AccessDenied => ErrorKindProperties {
+                status: StatusCode::FORBIDDEN,
+                kind: "access_denied",
+                title: "Access denied",
+            },
+            ErrorKind::InvalidPayload => ErrorKindProperties {
+                status: StatusCode::BAD_REQUEST,
+                kind: "invalid_payload",
+                title: "Invalid payload",
+            },
+            ErrorKind::MultipartUploadError => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "multipart_upload_error",
+                title: "Error performing a multipart upload",
+            },
+            ErrorKind::MultipartUploadNotFound => ErrorKindProperties {
+                status: StatusCode::NOT_FOUND,
+                kind: "multipart_upload_not_found",
+                title: "Multipart upload not found",
+            },
+            ErrorKind::ObjectListingError => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "error_listing_objects",
+                title: "Error listing objects in the set",
+            },
+            ErrorKind::BucketNotFound => ErrorKindProperties {
+                status: StatusCode::NOT_FOUND,
+                kind: "bucket_not_found",
+                title: "Bucket not found",
+            },
+            ErrorKind::ObjectNotFound => ErrorKindProperties {
+                status: StatusCode::NOT_FOUND,
+                kind: "object_not_found",
+                title: "Object not found",
+            },
+            ErrorKind::ObjectMetadataError => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "error_reading_object_metadata",
+                title: "Error reading metadata of the object in the set",
+            },
+            ErrorKind::ObjectDeletingError => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "error_deleting_object",
+                title: "Error deleting object in the set",
+            },
+            ErrorKind::ObjectCopyingError => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "error_copying_object",
+                title: "Error copying object between the sets",
+            },
+            ErrorKind::ObjectPartiallyMoved => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "object_partially_moved",
+                title: "Object copied to the target set but not deleted from the source set",
+            },
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Error {
     kind: ErrorKind,
     err: Option<Arc<anyhow::Error>>,
@@ -127,16 +199,6 @@ impl fmt::Debug for Error {
     }
 }
 
-pub trait ErrorExt<T> {
-    fn error(self, kind: ErrorKind) -> Result<T, Error>;
-}
-
-impl<T, E: Into<anyhow::Error>> ErrorExt<T> for Result<T, E> {
-    fn error(self, kind: ErrorKind) -> Result<T, Error> {
-        self.map_err(|source| Error::new(kind, Some(source.into())))
-    }
-}
-
 pub trait ErrorKindExt {
     fn kind(self, kind: ErrorKind) -> Error;
 }
@@ -1,6 +1,7 @@
 use axum::{
     body::Body,
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post},
     Extension, Router,
 };
 use http::{
@@ -8,18 +9,28 @@ use http::{
     Method, Response,
 };
 use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
 use tower_http::cors::{Any, CorsLayer};
-use tracing::error;
+use tracing::{error, info, warn};
 
-use super::{config::AppConfig, context::AppContext, endpoints};
+use super::{
+    config::AppConfig,
+    context::{build_cache, AppContext, ContextHandle},
+    endpoints,
+    maxmind::GeoReaders,
+    metrics,
+};
 
 pub fn build_router(
-    context: Arc<AppContext>,
-    authn: svc_authn::jose::ConfigMap,
-    maxmind: Arc<maxminddb::Reader<Vec<u8>>>,
+    context: ContextHandle,
+    config: &AppConfig,
+    maxmind: Arc<GeoReaders>,
 ) -> Router {
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::DELETE])
         .allow_headers([
             header::AUTHORIZATION,
             header::CACHE_CONTROL,
@@ -33,28 +44,103 @@ pub fn build_router(
             HeaderName::from_static("x-request-type"),
             HeaderName::from_static("x-agent-label"),
         ])
+        .expose_headers([
+            header::ACCEPT_RANGES,
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CONTENT_TYPE,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ])
         .max_age(std::time::Duration::from_secs(3600))
         .allow_origin(Any);
 
     let routes = Router::new().nest(
         "/api/v2",
         Router::new()
+            .route(
+                "/backends/:back/sets/:set/objects",
+                get(endpoints::backend_list),
+            )
             .route(
                 "/backends/:back/sets/:set/objects/:object",
-                get(endpoints::backend_read),
+                get(endpoints::backend_read)
+                    .head(endpoints::backend_head)
+                    .delete(endpoints::backend_delete),
+            )
+            .route("/sets/:set/objects", get(endpoints::default_list))
+            .route(
+                "/sets/:set/objects/:object",
+                get(endpoints::default_read)
+                    .head(endpoints::default_head)
+                    .delete(endpoints::default_delete),
+            )
+            .route("/sign", post(endpoints::default_sign))
+            .route("/sign/batch", post(endpoints::default_sign_batch))
+            .route("/sign/post", post(endpoints::default_sign_post))
+            .route("/copy", post(endpoints::default_copy))
+            .route("/move", post(endpoints::default_move))
+            .route(
+                "/sets/:set/objects/:object/multipart",
+                post(endpoints::default_multipart_initiate),
+            )
+            .route(
+                "/sets/:set/objects/:object/multipart/:upload_id",
+                delete(endpoints::default_multipart_abort),
+            )
+            .route(
+                "/sets/:set/objects/:object/multipart/:upload_id/sign",
+                post(endpoints::default_multipart_sign_part),
+            )
+            .route(
+                "/sets/:set/objects/:object/multipart/:upload_id/complete",
+                post(endpoints::default_multipart_complete),
             )
             .route("/backends/:back/sign", post(endpoints::backend_sign))
+            .route(
+                "/backends/:back/sign/batch",
+                post(endpoints::backend_sign_batch),
+            )
+            .route(
+                "/backends/:back/sign/post",
+                post(endpoints::backend_sign_post),
+            )
+            .route("/backends/:back/geo", get(endpoints::backend_geo))
+            .route("/backends/:back/copy", post(endpoints::backend_copy))
+            .route("/backends/:back/move", post(endpoints::backend_move))
+            .route(
+                "/backends/:back/sets/:set/objects/:object/multipart",
+                post(endpoints::multipart_initiate),
+            )
+            .route(
+                "/backends/:back/sets/:set/objects/:object/multipart/:upload_id",
+                delete(endpoints::multipart_abort),
+            )
+            .route(
+                "/backends/:back/sets/:set/objects/:object/multipart/:upload_id/sign",
+                post(endpoints::multipart_sign_part),
+            )
+            .route(
+                "/backends/:back/sets/:set/objects/:object/multipart/:upload_id/complete",
+                post(endpoints::multipart_complete),
+            )
+            .route_layer(middleware::from_fn(metrics::track_http))
             .layer(cors)
-            .layer(Extension(Arc::new(authn)))
-            .layer(Extension(Arc::new(context.application_id.clone())))
+            .layer(Extension(Arc::new(config.authn.clone())))
+            .layer(Extension(Arc::new(context.load().application_id.clone())))
             .layer(Extension(maxmind))
-            .with_state(context),
+            .layer(Extension(Arc::new(config.http.client_ip.clone())))
+            .layer(Extension(Arc::new(config.country_override.clone())))
+            .with_state(context.clone()),
     );
 
-    let pingz_router = Router::new().route(
-        "/healthz",
-        get(|| async { Response::builder().body(Body::from("pong")).unwrap() }),
-    );
+    let pingz_router = Router::new()
+        .route(
+            "/healthz",
+            get(|| async { Response::builder().body(Body::from("pong")).unwrap() }),
+        )
+        .route("/readyz", get(endpoints::readyz))
+        .with_state(context);
 
     let routes = routes.merge(pingz_router);
 
@@ -62,18 +148,91 @@ pub fn build_router(
 }
 
 pub async fn run(config: AppConfig) {
-    let ctx = Arc::new(AppContext::build(config.clone()));
+    let cache = build_cache();
+    let ctx = AppContext::build(config.clone(), cache.clone()).expect("Error building app context");
+    let ctx = ContextHandle::new(ctx, config.clone(), cache);
+
+    tokio::spawn(reload_on_hangup(ctx.clone()));
+
+    let metrics_server = config
+        .metrics
+        .as_ref()
+        .map(|metrics| svc_utils::metrics::MetricsServer::new(metrics.http.bind_address));
 
-    let reader =
-        Arc::new(maxminddb::Reader::open_readfile("maxmind.mmdb").expect("can't load maxminddb"));
+    let reader = Arc::new(GeoReaders::open(&config.maxmind));
+    reader.spawn_watcher(config.maxmind.check_interval);
 
-    if let Err(e) = axum::Server::bind(&config.http.listener_address)
+    let shutdown = ctx.shutdown().clone();
+    let close = Arc::new(Notify::new());
+    let server = axum::Server::bind(&config.http.listener_address)
         .serve(
-            build_router(ctx, config.authn.clone(), reader)
-                .into_make_service_with_connect_info::<SocketAddr>(),
+            build_router(ctx, &config, reader).into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .await
-    {
-        error!("Failed to await http server completion, err = {:?}", e);
+        .with_graceful_shutdown({
+            let close = close.clone();
+            async move { close.notified().await }
+        });
+
+    // Fail readiness first, then stop accepting connections and give
+    // in-flight requests the drain timeout to complete.
+    let drain = async {
+        shutdown_signal().await;
+        shutdown.raise();
+        tokio::time::sleep(config.http.shutdown_delay).await;
+
+        info!("Closing http listener");
+        close.notify_one();
+        tokio::time::sleep(config.http.drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                error!("Failed to await http server completion, err = {:?}", e);
+            }
+        }
+        _ = drain => {
+            warn!("Drain timeout exceeded, dropping in-flight requests");
+        }
+    }
+
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.shutdown().await;
+    }
+}
+
+/// Reloads the config on SIGHUP. Listener address, authn config and
+/// application id are only read on start.
+async fn reload_on_hangup(ctx: ContextHandle) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP, err = {:?}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("Reloading config");
+
+        let result = AppConfig::load()
+            .map_err(anyhow::Error::from)
+            .and_then(|config| ctx.reload(config));
+        match result {
+            Ok(()) => info!("Config has been reloaded"),
+            Err(e) => error!(
+                "Failed to reload config, keeping the current one, err = {:#}",
+                e
+            ),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
     }
 }
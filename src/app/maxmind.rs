@@ -1,48 +1,317 @@
-use crate::app::error::{Error, ErrorKind};
+use crate::{
+    app::{
+        config::{ClientIpConfig, CountryOverrideConfig, MaxmindConfig},
+        error::{Error, ErrorKind},
+        metrics::METRICS,
+    },
+    s3::Location,
+};
+use arc_swap::ArcSwapOption;
 use axum::{
     async_trait,
-    extract::{Extension, FromRequestParts},
+    extract::{ConnectInfo, Extension, FromRequestParts},
     http::request::Parts,
 };
-use axum_client_ip::InsecureClientIp;
-use maxminddb::{geoip2::Country, Reader};
-use std::sync::Arc;
-use tracing::{error, field, Span};
+use maxminddb::{
+    geoip2::{Asn, Country},
+    Reader,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use svc_utils::extractors::AccountIdExtractor;
+use tracing::{error, field, info, warn, Span};
+
+use self::continents::continent_of;
+
+mod continents;
+
+/// A maxmind database reloaded once its file is replaced.
+struct Database {
+    name: &'static str,
+    path: PathBuf,
+    reader: ArcSwapOption<Reader<Vec<u8>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Database {
+    fn open(name: &'static str, path: &Path) -> Self {
+        let db = Self {
+            name,
+            path: path.to_owned(),
+            reader: ArcSwapOption::empty(),
+            modified: Mutex::new(None),
+        };
+
+        if !db.reload() {
+            METRICS.maxmind_loaded.with_label_values(&[name]).set(0);
+            warn!(
+                "maxmind {} database {} is not loaded, resolving no locations with it",
+                name,
+                path.display()
+            );
+        }
+        db
+    }
+
+    /// Swaps the reader if the file has been modified since the last load.
+    /// The current reader is kept if the new file is invalid.
+    fn reload(&self) -> bool {
+        let modified = std::fs::metadata(&self.path).and_then(|meta| meta.modified());
+        let mut last_modified = self.modified.lock().expect("poisoned maxmind mutex");
+        let modified = match modified {
+            Ok(modified) if Some(modified) == *last_modified => return false,
+            Ok(modified) => modified,
+            Err(err) => {
+                if last_modified.is_some() {
+                    error!(
+                        "failed to read maxmind {} database {}: {}",
+                        self.name,
+                        self.path.display(),
+                        err
+                    );
+                }
+                return false;
+            }
+        };
+
+        // Remember the modification time even on failure not to reopen
+        // a broken file on each check.
+        *last_modified = Some(modified);
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                info!(
+                    "loaded maxmind {} database {} of build {}",
+                    self.name,
+                    self.path.display(),
+                    reader.metadata.build_epoch
+                );
+                self.reader.store(Some(Arc::new(reader)));
+                METRICS
+                    .maxmind_loaded
+                    .with_label_values(&[self.name])
+                    .set(1);
+                true
+            }
+            Err(err) => {
+                error!(
+                    "failed to load maxmind {} database {}: {}",
+                    self.name,
+                    self.path.display(),
+                    err
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Maxmind databases used to resolve a location of a client.
+/// A location without a country is resolved until the country database is loaded.
+pub struct GeoReaders {
+    country: Database,
+    asn: Option<Database>,
+}
+
+impl GeoReaders {
+    pub fn open(config: &MaxmindConfig) -> Self {
+        Self {
+            country: Database::open("country", &config.path),
+            asn: config
+                .asn_path
+                .as_ref()
+                .map(|path| Database::open("asn", path)),
+        }
+    }
+
+    /// Reloads replaced databases in background until readers are dropped.
+    pub fn spawn_watcher(self: &Arc<Self>, check_interval: Duration) {
+        let readers = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            // The first tick completes immediately, databases have just been opened.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+
+                let readers = match readers.upgrade() {
+                    Some(readers) => readers,
+                    None => return,
+                };
+                readers.country.reload();
+                if let Some(ref asn) = readers.asn {
+                    asn.reload();
+                }
+            }
+        });
+    }
+
+    fn lookup(&self, ip_address: IpAddr) -> Location {
+        let country_reader = self.country.reader.load();
+        let (country, continent) = match country_reader
+            .as_ref()
+            .map(|reader| reader.lookup::<Country>(ip_address))
+        {
+            Some(Ok(country)) => (
+                country.country.and_then(|c| c.iso_code),
+                country.continent.and_then(|c| c.code),
+            ),
+            Some(Err(err)) => {
+                error!("maxmind db error: {}", err);
+                METRICS.maxmind_failures.inc();
+                (None, None)
+            }
+            None => (None, None),
+        };
+
+        let asn_reader = self.asn.as_ref().map(|asn| asn.reader.load());
+        let asn = asn_reader
+            .as_ref()
+            .and_then(|reader| reader.as_ref())
+            .and_then(|reader| match reader.lookup::<Asn>(ip_address) {
+                Ok(asn) => asn.autonomous_system_number,
+                Err(err) => {
+                    error!("maxmind asn db error: {}", err);
+                    METRICS.maxmind_failures.inc();
+                    None
+                }
+            });
+
+        Location {
+            country: country.map(|c| c.to_lowercase()),
+            continent: continent.map(|c| c.to_lowercase()),
+            asn,
+        }
+    }
+}
 
-/// Extracts iso code of country from ip address.
-pub struct CountryExtractor(pub Option<String>);
+/// An ip address of a client and its location.
+pub struct ClientGeo {
+    pub ip_address: Option<IpAddr>,
+    pub location: Location,
+    /// Whether the country has been overridden with a header of a trusted account.
+    pub overridden: bool,
+}
 
 #[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for CountryExtractor {
+impl<S: Send + Sync> FromRequestParts<S> for ClientGeo {
     type Rejection = Error;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         use axum::RequestPartsExt;
 
         let Extension(maxmind) = parts
-            .extract::<Extension<Arc<Reader<Vec<u8>>>>>()
+            .extract::<Extension<Arc<GeoReaders>>>()
             .await
             .ok()
             .ok_or(Error::new(ErrorKind::MissingMaxmind, None))?;
 
-        let Ok(InsecureClientIp(ip_address)) = InsecureClientIp::from_request_parts(parts, state).await else {
-            error!("error retrieve ip address");
-            return Ok(Self(None));
-        };
-
-        Span::current().record("ip_address", &field::display(&ip_address));
+        let ip_address =
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(
+                    |ConnectInfo(peer)| match parts.extensions.get::<Arc<ClientIpConfig>>() {
+                        Some(client_ip) => client_ip.resolve(peer.ip(), &parts.headers),
+                        None => peer.ip(),
+                    },
+                );
 
-        let country: Option<String> = match maxmind.lookup::<Country>(ip_address) {
-            Ok(country) => country
-                .country
-                .and_then(|c| c.iso_code)
-                .map(|c| c.to_string()),
-            Err(err) => {
-                error!("maxmind db error: {}", err);
-                None
+        let mut geo = match ip_address {
+            Some(ip_address) => {
+                Span::current().record("ip_address", field::display(&ip_address));
+                Self {
+                    ip_address: Some(ip_address),
+                    location: maxmind.lookup(ip_address),
+                    overridden: false,
+                }
+            }
+            None => {
+                error!("error retrieve ip address");
+                Self {
+                    ip_address: None,
+                    location: Location::default(),
+                    overridden: false,
+                }
             }
         };
 
-        Ok(Self(country))
+        if let Some(country) = override_country(parts, state).await {
+            // The network of the client is unknown, only the continent follows from the country.
+            geo.location = Location {
+                continent: continent_of(&country).map(ToOwned::to_owned),
+                country: Some(country),
+                asn: None,
+            };
+            geo.overridden = true;
+        }
+
+        Ok(geo)
+    }
+}
+
+/// A country of the override header if the request is authenticated
+/// with one of trusted accounts.
+async fn override_country<S: Send + Sync>(parts: &mut Parts, state: &S) -> Option<String> {
+    let config = parts
+        .extensions
+        .get::<Arc<CountryOverrideConfig>>()?
+        .clone();
+    if config.accounts.is_empty() {
+        return None;
+    }
+
+    let country = parts
+        .headers
+        .get(config.header.as_str())?
+        .to_str()
+        .ok()?
+        .trim()
+        .to_lowercase();
+
+    match AccountIdExtractor::from_request_parts(parts, state).await {
+        Ok(AccountIdExtractor(account)) if config.accounts.contains(&account) => Some(country),
+        Ok(AccountIdExtractor(account)) => {
+            warn!("ignoring country override of untrusted account {}", account);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Extracts country, continent and autonomous system of a client from ip address.
+pub struct LocationExtractor(pub Location);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LocationExtractor {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let geo = ClientGeo::from_request_parts(parts, state).await?;
+        Ok(Self(geo.location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_without_databases() {
+        let readers = GeoReaders::open(&MaxmindConfig {
+            path: PathBuf::from("/nonexistent/maxmind.mmdb"),
+            asn_path: Some(PathBuf::from("/nonexistent/asn.mmdb")),
+            ..Default::default()
+        });
+
+        assert!(!readers.country.reload());
+        assert_eq!(
+            readers.lookup("192.0.2.1".parse().unwrap()),
+            Location::default()
+        );
     }
 }
@@ -0,0 +1,74 @@
+/// ISO 3166-1 country codes by continent codes of maxmind databases.
+const CONTINENTS: [(&str, &[&str]); 7] = [
+    (
+        "af",
+        &[
+            "ao", "bf", "bi", "bj", "bw", "cd", "cf", "cg", "ci", "cm", "cv", "dj", "dz", "eg",
+            "eh", "er", "et", "ga", "gh", "gm", "gn", "gq", "gw", "ke", "km", "lr", "ls", "ly",
+            "ma", "mg", "ml", "mr", "mu", "mw", "mz", "na", "ne", "ng", "re", "rw", "sc", "sd",
+            "sh", "sl", "sn", "so", "ss", "st", "sz", "td", "tg", "tn", "tz", "ug", "yt", "za",
+            "zm", "zw",
+        ],
+    ),
+    ("an", &["aq", "bv", "gs", "hm", "tf"]),
+    (
+        "as",
+        &[
+            "ae", "af", "am", "az", "bd", "bh", "bn", "bt", "cc", "cn", "cx", "ge", "hk", "id",
+            "il", "in", "io", "iq", "ir", "jo", "jp", "kg", "kh", "kp", "kr", "kw", "kz", "la",
+            "lb", "lk", "mm", "mn", "mo", "mv", "my", "np", "om", "ph", "pk", "ps", "qa", "sa",
+            "sg", "sy", "th", "tj", "tl", "tm", "tr", "tw", "uz", "vn", "ye",
+        ],
+    ),
+    (
+        "eu",
+        &[
+            "ad", "al", "at", "ax", "ba", "be", "bg", "by", "ch", "cy", "cz", "de", "dk", "ee",
+            "es", "fi", "fo", "fr", "gb", "gg", "gi", "gr", "hr", "hu", "ie", "im", "is", "it",
+            "je", "li", "lt", "lu", "lv", "mc", "md", "me", "mk", "mt", "nl", "no", "pl", "pt",
+            "ro", "rs", "ru", "se", "si", "sj", "sk", "sm", "ua", "va", "xk",
+        ],
+    ),
+    (
+        "na",
+        &[
+            "ag", "ai", "aw", "bb", "bl", "bm", "bq", "bs", "bz", "ca", "cr", "cu", "cw", "dm",
+            "do", "gd", "gl", "gp", "gt", "hn", "ht", "jm", "kn", "ky", "lc", "mf", "mq", "ms",
+            "mx", "ni", "pa", "pm", "pr", "sv", "sx", "tc", "tt", "us", "vc", "vg", "vi",
+        ],
+    ),
+    (
+        "oc",
+        &[
+            "as", "au", "ck", "fj", "fm", "gu", "ki", "mh", "mp", "nc", "nf", "nr", "nu", "nz",
+            "pf", "pg", "pn", "pw", "sb", "tk", "to", "tv", "um", "vu", "wf", "ws",
+        ],
+    ),
+    (
+        "sa",
+        &[
+            "ar", "bo", "br", "cl", "co", "ec", "fk", "gf", "gy", "pe", "py", "sr", "uy", "ve",
+        ],
+    ),
+];
+
+/// A continent code of a lowercase country code.
+pub(super) fn continent_of(country: &str) -> Option<&'static str> {
+    CONTINENTS
+        .iter()
+        .find(|(_, countries)| countries.contains(&country))
+        .map(|(continent, _)| *continent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continent_of_test() {
+        assert_eq!(continent_of("ru"), Some("eu"));
+        assert_eq!(continent_of("us"), Some("na"));
+        assert_eq!(continent_of("as"), Some("oc"));
+        assert_eq!(continent_of("zz"), None);
+    }
+}
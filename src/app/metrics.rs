@@ -0,0 +1,140 @@
+use axum::{extract::MatchedPath, middleware::Next, response::Response};
+use http::Request;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
+use std::time::{Duration, Instant};
+
+use super::error::ErrorKind;
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub authz_duration: HistogramVec,
+    pub signatures: IntCounterVec,
+    pub proxy_hosts: IntCounterVec,
+    pub proxy_hosts_up: IntGaugeVec,
+    pub maxmind_failures: IntCounter,
+    pub maxmind_loaded: IntGaugeVec,
+    pub backend_failovers: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            http_requests: register_int_counter_vec!(
+                "http_requests_total",
+                "HTTP requests by route, status and error kind",
+                &["route", "method", "status", "error_kind"]
+            )
+            .expect("Can't create http_requests_total metric"),
+            http_request_duration: register_histogram_vec!(
+                "http_request_duration_seconds",
+                "HTTP request duration by route",
+                &["route", "method"]
+            )
+            .expect("Can't create http_request_duration_seconds metric"),
+            authz_duration: register_histogram_vec!(
+                "authz_duration_seconds",
+                "Authz call duration by audience and outcome",
+                &["audience", "outcome"]
+            )
+            .expect("Can't create authz_duration_seconds metric"),
+            signatures: register_int_counter_vec!(
+                "signatures_total",
+                "Signatures issued by backend and method",
+                &["backend", "method"]
+            )
+            .expect("Can't create signatures_total metric"),
+            proxy_hosts: register_int_counter_vec!(
+                "proxy_host_selections_total",
+                "Proxy hosts selected by country",
+                &["country", "host"]
+            )
+            .expect("Can't create proxy_host_selections_total metric"),
+            proxy_hosts_up: register_int_gauge_vec!(
+                "proxy_host_up",
+                "Health of proxy hosts by backend",
+                &["backend", "host"]
+            )
+            .expect("Can't create proxy_host_up metric"),
+            maxmind_failures: register_int_counter!(
+                "maxmind_lookup_failures_total",
+                "Failed maxmind lookups"
+            )
+            .expect("Can't create maxmind_lookup_failures_total metric"),
+            maxmind_loaded: register_int_gauge_vec!(
+                "maxmind_database_loaded",
+                "Whether a maxmind database is loaded",
+                &["database"]
+            )
+            .expect("Can't create maxmind_database_loaded metric"),
+            backend_failovers: register_int_counter_vec!(
+                "backend_failovers_total",
+                "Reads failed over from a backend by fallback",
+                &["backend", "fallback"]
+            )
+            .expect("Can't create backend_failovers_total metric"),
+        }
+    }
+
+    pub fn observe_authz(&self, audience: &str, outcome: &str, duration: Duration) {
+        self.authz_duration
+            .with_label_values(&[audience, outcome])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn inc_signatures(&self, backend: &str, method: &str) {
+        self.signatures.with_label_values(&[backend, method]).inc();
+    }
+
+    pub fn inc_proxy_host(&self, country: &str, host: &str) {
+        self.proxy_hosts.with_label_values(&[country, host]).inc();
+    }
+
+    pub fn inc_backend_failover(&self, backend: &str, fallback: &str) {
+        self.backend_failovers
+            .with_label_values(&[backend, fallback])
+            .inc();
+    }
+
+    pub fn set_proxy_host_up(&self, backend: &str, host: &str, up: bool) {
+        self.proxy_hosts_up
+            .with_label_values(&[backend, host])
+            .set(up as i64);
+    }
+}
+
+/// Counts requests of a matched route labeled with the `ErrorKind`
+/// the response has been created of.
+pub async fn track_http<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let method = req.method().clone();
+    let started_at = Instant::now();
+
+    let resp = next.run(req).await;
+
+    let error_kind = resp
+        .extensions()
+        .get::<ErrorKind>()
+        .map(|kind| kind.kind())
+        .unwrap_or_default();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&route, method.as_str()])
+        .observe(started_at.elapsed().as_secs_f64());
+    METRICS
+        .http_requests
+        .with_label_values(&[&route, method.as_str(), resp.status().as_str(), error_kind])
+        .inc();
+
+    resp
+}
@@ -1,4 +1,5 @@
 mod authz;
+mod client_ip;
 mod context;
 mod endpoints;
 mod error;
@@ -6,4 +7,5 @@ mod maxmind;
 
 pub mod config;
 pub mod http;
+pub mod metrics;
 pub mod util;
@@ -1,82 +1,444 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use radix_trie::Trie;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, HashMap},
     fmt,
-    ops::Deref,
     sync::Arc,
+    time::Duration,
 };
-use svc_authn::{AccountId, Authenticable};
+use svc_authn::AccountId;
 
-use crate::s3::Client;
+use crate::{
+    app::config::ReadMode,
+    credentials::{CredentialsConfig, CredentialsProvider, Secret},
+    s3::{Client, Location},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub type S3Clients = BTreeMap<String, Arc<Client>>;
+/// Clients of backends by name, an alias resolves to the client of its backend.
+#[derive(Debug, Default)]
+pub struct S3Clients {
+    clients: BTreeMap<String, Arc<Client>>,
+    aliases: BTreeMap<String, String>,
+}
+
+impl S3Clients {
+    pub fn get(&self, back: &str) -> Option<&Arc<Client>> {
+        let back = self.aliases.get(back).map(String::as_str).unwrap_or(back);
+        self.clients.get(back)
+    }
+
+    /// Clients by backend name, aliases aren't included.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<Client>)> {
+        self.clients.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Arc<Client>> {
+        self.clients.values()
+    }
+
+    fn insert_alias(&mut self, alias: &str, back: &str) -> Result<()> {
+        if self.clients.contains_key(alias) || self.aliases.contains_key(alias) {
+            bail!(
+                "Alias '{}' of the backend '{}' is already taken",
+                alias,
+                back
+            );
+        }
+
+        self.aliases.insert(alias.to_owned(), back.to_owned());
+        Ok(())
+    }
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct BackendConfig(BTreeMap<String, BackendConfigItem>);
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct BackendConfigItem {
     proxy_hosts: Option<HashMap<String, Vec<ProxyHost>>>,
+    #[serde(default)]
+    proxy_health_check: ProxyHealthCheck,
+    #[serde(default)]
+    proxy_strategy: ProxyStrategy,
+    #[serde(default)]
+    read_mode: ReadMode,
+    cdn: Option<CdnConfig>,
+    /// Other names the backend is available by in the API.
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Backends holding replicas of objects, reads fail over to them in order.
+    #[serde(default)]
+    fallbacks: Vec<String>,
+    #[serde(default)]
+    signature_version: SignatureVersion,
+    #[serde(default)]
+    addressing_style: AddressingStyle,
+    #[serde(default)]
+    credentials: CredentialsConfig,
+    endpoint: Option<String>,
+    region: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct ProxyHost {
     pub base: String,
     pub alias_range_upper_bound: Option<usize>,
+    /// A share of requests relative to other hosts of the country.
+    #[serde(default = "ProxyHost::default_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub kind: ProxyKind,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    /// Signed S3 URLs are rewritten to the host.
+    #[default]
+    Proxy,
+    /// URLs of reads are signed with a token of the backend `cdn` config.
+    Cdn,
+}
+
+/// A token of URLs signed for CDN hosts.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CdnConfig {
+    /// `md5` and `expires` query parameters checked by the nginx `secure_link` module.
+    SecureLink { secret: Secret },
+    /// `expires`, `key_id` and `signature` query parameters
+    /// of HMAC-SHA256 of the expiration time, the key id and the path.
+    HmacSha256 {
+        key_id: Option<String>,
+        secret: Secret,
+    },
+}
+
+/// A version of AWS Signature of signed URLs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureVersion {
+    /// Legacy HMAC-SHA1 signature for backends without Signature Version 4 support.
+    V2,
+    #[default]
+    V4,
+}
+
+/// Where a bucket is put in signed URLs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressingStyle {
+    /// `https://{endpoint}/{bucket}/{object}`
+    #[default]
+    Path,
+    /// `https://{bucket}.{endpoint}/{object}`
+    VirtualHosted,
+}
+
+impl ProxyHost {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+/// How a proxy host of a route is picked for a signed URL.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyStrategy {
+    /// Spread URLs across hosts with weighted round-robin.
+    #[default]
+    RoundRobin,
+    /// Always pick the same host for an object to make use of its cache.
+    Object,
+    /// Always pick the same host for an account.
+    Account,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ProxyHealthCheck {
+    pub path: String,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    pub interval: Duration,
+    #[serde(deserialize_with = "crate::serde::duration")]
+    pub timeout: Duration,
+    /// Consecutive passed checks to consider an unhealthy host healthy again.
+    pub healthy_threshold: u32,
+    /// Consecutive failed checks to consider a healthy host unhealthy.
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for ProxyHealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/".to_owned(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn read_s3_config(config: &BackendConfig) -> Result<S3Clients> {
-    let mut acc = S3Clients::new();
+    update_s3_config(config, &BackendConfig::default(), &S3Clients::default())
+}
+
+/// Same as `read_s3_config` but reuses `current` clients of backends
+/// whose config hasn't changed, keeping their cached credentials.
+pub fn update_s3_config(
+    config: &BackendConfig,
+    current_config: &BackendConfig,
+    current: &S3Clients,
+) -> Result<S3Clients> {
+    let mut acc = S3Clients::default();
+
+    for (back, item) in config.0.iter() {
+        match current.clients.get(back) {
+            Some(client) if current_config.0.get(back) == Some(item) => {
+                acc.clients.insert(back.to_owned(), client.clone());
+            }
+            _ => read_s3(back, &format!("{}_", back.to_uppercase()), item, &mut acc)?,
+        }
+    }
 
-    for (back, config) in config.0.iter() {
-        read_s3(back, &format!("{}_", back.to_uppercase()), config, &mut acc);
+    for (back, item) in config.0.iter() {
+        for alias in &item.aliases {
+            acc.insert_alias(alias, back)?;
+        }
+    }
+
+    for (back, item) in config.0.iter() {
+        for fallback in &item.fallbacks {
+            match acc.get(fallback) {
+                Some(client) if client.name() != back => {}
+                _ => bail!("Backend '{}' has an invalid fallback '{}'", back, fallback),
+            }
+        }
     }
 
     Ok(acc)
 }
 
-fn read_s3(back: &str, prefix: &str, item: &BackendConfigItem, acc: &mut S3Clients) {
+fn read_s3(back: &str, prefix: &str, item: &BackendConfigItem, acc: &mut S3Clients) -> Result<()> {
     use std::env::var;
-    let key = var(format!("{}AWS_ACCESS_KEY_ID", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_ACCESS_KEY_ID must be specified", prefix));
-    let secret = var(format!("{}AWS_SECRET_ACCESS_KEY", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_SECRET_ACCESS_KEY must be specified", prefix));
-    let endpoint = var(format!("{}AWS_ENDPOINT", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_ENDPOINT must be specified", prefix));
-    let region = var(format!("{}AWS_REGION", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_REGION must be specified", prefix));
+    let credentials = CredentialsProvider::from_config(&item.credentials, prefix)
+        .with_context(|| format!("Invalid credentials of the backend '{}'", back))?;
+    let endpoint = match item.endpoint {
+        Some(ref endpoint) => endpoint.to_owned(),
+        None => var(format!("{}AWS_ENDPOINT", prefix))
+            .with_context(|| format!("{}AWS_ENDPOINT must be specified", prefix))?,
+    };
+    let region = match item.region {
+        Some(ref region) => region.to_owned(),
+        None => var(format!("{}AWS_REGION", prefix))
+            .with_context(|| format!("{}AWS_REGION must be specified", prefix))?,
+    };
 
     let mut client = Client::new(
-        &key,
-        &secret,
+        back,
+        credentials,
         &region,
         &endpoint,
-        ::std::time::Duration::from_secs(300),
+        Duration::from_secs(300),
     );
 
+    client
+        .set_read_mode(item.read_mode)
+        .set_signing(item.signature_version, item.addressing_style)
+        .set_fallbacks(&item.fallbacks);
     if let Some(ref proxy_hosts) = item.proxy_hosts {
-        client.set_proxy_hosts(proxy_hosts);
+        let has_cdn_hosts = proxy_hosts
+            .values()
+            .flatten()
+            .any(|host| host.kind == ProxyKind::Cdn);
+        if has_cdn_hosts && item.cdn.is_none() {
+            bail!("Backend '{}' has cdn proxy hosts but no cdn config", back);
+        }
+        // Proxy hosts replace the host of a URL which holds the bucket in the virtual hosted style.
+        let has_proxy_hosts = proxy_hosts
+            .values()
+            .flatten()
+            .any(|host| host.kind == ProxyKind::Proxy);
+        if has_proxy_hosts && item.addressing_style == AddressingStyle::VirtualHosted {
+            bail!(
+                "Backend '{}' has proxy hosts which don't support the virtual hosted style",
+                back
+            );
+        }
+
+        client
+            .set_proxy_hosts(proxy_hosts, item.proxy_strategy)
+            .set_proxy_health_check(&item.proxy_health_check);
+    }
+    if let Some(ref cdn) = item.cdn {
+        client.set_cdn(cdn);
     }
 
-    acc.insert(back.to_owned(), Arc::new(client));
+    acc.clients.insert(back.to_owned(), Arc::new(client));
+    Ok(())
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Query parameters of a signed GET request overriding headers of the response.
+const RESPONSE_OVERRIDES: [&str; 6] = [
+    "response-cache-control",
+    "response-content-disposition",
+    "response-content-encoding",
+    "response-content-language",
+    "response-content-type",
+    "response-expires",
+];
+
+/// Validated `response-*` query parameters of a signed GET request.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseOverrides(BTreeMap<String, String>);
+
+impl ResponseOverrides {
+    pub fn new(params: BTreeMap<String, String>) -> Result<Self> {
+        for (key, value) in &params {
+            if !RESPONSE_OVERRIDES.contains(&key.as_str()) {
+                bail!(
+                    "'{}' can't be overridden, allowed are {}",
+                    key,
+                    RESPONSE_OVERRIDES.join(", ")
+                );
+            }
+            if http::HeaderValue::from_str(value).is_err() {
+                bail!("invalid value of '{}'", key);
+            }
+        }
+
+        Ok(Self(params))
+    }
+
+    /// Picks `response-*` parameters of a query.
+    pub fn from_query(query: BTreeMap<String, String>) -> Result<Self> {
+        Self::new(
+            query
+                .into_iter()
+                .filter(|(key, _)| key.starts_with("response-"))
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, val)| (key.as_str(), val.as_str()))
+    }
+}
+
+/// A metadata key of an uploaded object the account of the uploader is put in.
+pub const ACCOUNT_ID_METADATA: &str = "account-id";
+/// Limits of user-defined metadata and tags of an object imposed by S3.
+const MAX_METADATA_SIZE: usize = 2048;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_KEY_LENGTH: usize = 128;
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// Characters escaped in keys and values of the `x-amz-tagging` header.
+const TAGGING: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Validated `x-amz-meta-*` and `x-amz-tagging` headers of a signed upload.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectAttributes(BTreeMap<String, String>);
+
+impl ObjectAttributes {
+    /// Metadata keys are given without the `x-amz-meta-` prefix,
+    /// the account of the uploader is always put in the `account-id` one.
+    pub fn new(
+        metadata: &BTreeMap<String, String>,
+        tags: &BTreeMap<String, String>,
+        account: &AccountId,
+    ) -> Result<Self> {
+        let mut headers = BTreeMap::new();
+
+        let mut size = 0;
+        let account = account.to_string();
+        let metadata = metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(std::iter::once((ACCOUNT_ID_METADATA, account.as_str())));
+        for (key, value) in metadata {
+            let header = format!("x-amz-meta-{}", key);
+            if headers.contains_key(&header) {
+                bail!("metadata key '{}' is reserved", key);
+            }
+            if key.is_empty() || http::HeaderName::from_lowercase(header.as_bytes()).is_err() {
+                bail!("invalid metadata key '{}'", key);
+            }
+            if !value.is_ascii() || http::HeaderValue::from_str(value).is_err() {
+                bail!("invalid value of metadata key '{}'", key);
+            }
+
+            size += key.len() + value.len();
+            headers.insert(header, value.to_owned());
+        }
+        if size > MAX_METADATA_SIZE {
+            bail!("metadata must not exceed {} bytes", MAX_METADATA_SIZE);
+        }
+
+        if tags.len() > MAX_TAGS {
+            bail!("number of tags must not exceed {}", MAX_TAGS);
+        }
+        for (key, value) in tags {
+            if key.is_empty() || key.chars().count() > MAX_TAG_KEY_LENGTH {
+                bail!(
+                    "tag key '{}' must be 1 to {} characters long",
+                    key,
+                    MAX_TAG_KEY_LENGTH
+                );
+            }
+            if value.chars().count() > MAX_TAG_VALUE_LENGTH {
+                bail!(
+                    "value of tag '{}' must not exceed {} characters",
+                    key,
+                    MAX_TAG_VALUE_LENGTH
+                );
+            }
+        }
+        if !tags.is_empty() {
+            let tagging = tags
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{}={}",
+                        percent_encoding::utf8_percent_encode(key, TAGGING),
+                        percent_encoding::utf8_percent_encode(value, TAGGING)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            headers.insert("x-amz-tagging".to_owned(), tagging);
+        }
+
+        Ok(Self(headers))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, val)| (key.as_str(), val.as_str()))
+    }
+}
+
 #[derive(Debug)]
 pub struct S3SignedRequestBuilder {
     method: Option<String>,
     bucket: Option<String>,
     object: Option<String>,
     headers: BTreeMap<String, String>,
+    params: BTreeMap<String, String>,
+    expires_in: Option<Duration>,
 }
 
 impl S3SignedRequestBuilder {
@@ -86,6 +448,8 @@ impl S3SignedRequestBuilder {
             bucket: None,
             object: None,
             headers: BTreeMap::new(),
+            params: BTreeMap::new(),
+            expires_in: None,
         }
     }
 
@@ -116,7 +480,37 @@ impl S3SignedRequestBuilder {
         Self { headers, ..self }
     }
 
-    pub fn build(self, client: &Client, country: Option<String>) -> Result<String> {
+    pub fn add_param(self, key: &str, value: &str) -> Self {
+        let mut params = self.params;
+        params.insert(key.to_string(), value.to_string());
+        Self { params, ..self }
+    }
+
+    pub fn response_overrides(self, overrides: &ResponseOverrides) -> Self {
+        overrides
+            .iter()
+            .fold(self, |builder, (key, val)| builder.add_param(key, val))
+    }
+
+    pub fn object_attributes(self, attributes: &ObjectAttributes) -> Self {
+        attributes
+            .iter()
+            .fold(self, |builder, (key, val)| builder.add_header(key, val))
+    }
+
+    pub fn expires_in(self, value: Duration) -> Self {
+        Self {
+            expires_in: Some(value),
+            ..self
+        }
+    }
+
+    pub async fn build(
+        self,
+        client: &Client,
+        location: Location,
+        account: &AccountId,
+    ) -> Result<String> {
         let mut req = client.create_request(
             &self
                 .method
@@ -131,9 +525,14 @@ impl S3SignedRequestBuilder {
         for (key, val) in self.headers {
             req.add_header(&key, &val);
         }
+        for (key, val) in self.params {
+            req.add_param(key, val);
+        }
 
+        let expires_in = self.expires_in.unwrap_or_else(|| client.expires_in());
         client
-            .sign_request(&mut req, country)
+            .sign_request(&mut req, location, account, &expires_in)
+            .await
             .map_err(|err| anyhow!("Error building a signed request. {}", &err.to_string()))
     }
 }
@@ -243,50 +642,20 @@ impl fmt::Display for Set {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
-pub struct Subject {
-    inner: AccountId,
-}
-
-impl Subject {
-    pub fn new(inner: AccountId) -> Self {
-        Self { inner }
-    }
-}
-
-impl Deref for Subject {
-    type Target = AccountId;
-
-    fn deref(&self) -> &AccountId {
-        &self.inner
-    }
-}
-
-impl Authenticable for Subject {
-    fn as_account_id(&self) -> &AccountId {
-        &self.inner
-    }
-}
-
-////////////////////////////////////////////////////////////////////////////////
-
-mod jose {
-    use svc_authn::jose::Claims;
-
-    use super::Subject;
-    use svc_authn::AccountId;
-
-    impl From<Claims<String>> for Subject {
-        fn from(value: Claims<String>) -> Self {
-            Self::new(AccountId::new(value.subject(), value.audience()))
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use crate::app::util::{read_s3_config, BackendConfig, BackendConfigItem, ProxyHost};
-    use std::collections::{BTreeMap, HashMap};
+    use crate::{
+        app::util::{
+            read_s3_config, update_s3_config, BackendConfig, BackendConfigItem, ObjectAttributes,
+            ProxyHost, ProxyKind, ResponseOverrides,
+        },
+        credentials::CredentialsConfig,
+    };
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+    };
+    use svc_authn::AccountId;
 
     #[test]
     fn read_s3_config_test() {
@@ -294,20 +663,48 @@ mod tests {
         let ua_host = ProxyHost {
             base: "ua.example.org".to_string(),
             alias_range_upper_bound: Some(2),
+            weight: 1,
+            kind: ProxyKind::Proxy,
         };
         hosts.insert("ua".to_string(), vec![ua_host]);
 
         let es_host = ProxyHost {
             base: "es.example.org".to_string(),
             alias_range_upper_bound: None,
+            weight: 1,
+            kind: ProxyKind::Proxy,
         };
         hosts.insert("es".to_string(), vec![es_host]);
 
         let item_with_proxy = BackendConfigItem {
             proxy_hosts: Some(hosts),
+            proxy_health_check: Default::default(),
+            proxy_strategy: Default::default(),
+            read_mode: Default::default(),
+            cdn: None,
+            aliases: vec![],
+            fallbacks: vec!["amazon".to_string()],
+            signature_version: Default::default(),
+            addressing_style: Default::default(),
+            credentials: CredentialsConfig::Env,
+            endpoint: None,
+            region: None,
         };
 
-        let item_without_proxy = BackendConfigItem { proxy_hosts: None };
+        let item_without_proxy = BackendConfigItem {
+            proxy_hosts: None,
+            proxy_health_check: Default::default(),
+            proxy_strategy: Default::default(),
+            read_mode: Default::default(),
+            cdn: None,
+            aliases: vec![],
+            fallbacks: vec![],
+            signature_version: Default::default(),
+            addressing_style: Default::default(),
+            credentials: CredentialsConfig::Env,
+            endpoint: None,
+            region: None,
+        };
 
         let mut config = BTreeMap::new();
         config.insert("yandex".to_string(), item_with_proxy);
@@ -319,7 +716,128 @@ mod tests {
             }
         }
 
-        let s3_clients = read_s3_config(&BackendConfig(config)).expect("s3 clients");
-        assert_eq!(s3_clients.len(), 2);
+        let mut config = BackendConfig(config);
+        config.0.get_mut("amazon").unwrap().aliases = vec!["default".to_string()];
+        let s3_clients = read_s3_config(&config).expect("s3 clients");
+        assert_eq!(s3_clients.iter().count(), 2);
+        assert_eq!(
+            s3_clients.get("yandex").unwrap().fallbacks(),
+            ["amazon".to_string()]
+        );
+        assert_eq!(s3_clients.get("default").unwrap().name(), "amazon");
+
+        config.0.get_mut("yandex").unwrap().aliases = vec!["default".to_string()];
+        assert!(read_s3_config(&config).is_err());
+
+        config.0.get_mut("yandex").unwrap().aliases = vec![];
+        config.0.get_mut("amazon").unwrap().fallbacks = vec!["google".to_string()];
+        assert!(read_s3_config(&config).is_err());
+    }
+
+    #[test]
+    fn update_s3_config_test() {
+        let item = |region: &str| BackendConfigItem {
+            proxy_hosts: None,
+            proxy_health_check: Default::default(),
+            proxy_strategy: Default::default(),
+            read_mode: Default::default(),
+            cdn: None,
+            aliases: Vec::new(),
+            fallbacks: Vec::new(),
+            signature_version: Default::default(),
+            addressing_style: Default::default(),
+            credentials: CredentialsConfig::Env,
+            endpoint: Some("http://localhost:9000".to_string()),
+            region: Some(region.to_string()),
+        };
+        for var in ["ACCESS_KEY_ID", "SECRET_ACCESS_KEY"] {
+            std::env::set_var(format!("KEPT_AWS_{}", var), "test");
+            std::env::set_var(format!("CHANGED_AWS_{}", var), "test");
+        }
+
+        let mut config = BTreeMap::new();
+        config.insert("kept".to_string(), item("a"));
+        config.insert("changed".to_string(), item("a"));
+        let config = BackendConfig(config);
+        let clients = read_s3_config(&config).expect("s3 clients");
+
+        let mut updated = BTreeMap::new();
+        updated.insert("kept".to_string(), item("a"));
+        updated.insert("changed".to_string(), item("b"));
+        let updated = update_s3_config(&BackendConfig(updated), &config, &clients)
+            .expect("updated s3 clients");
+
+        assert!(Arc::ptr_eq(
+            clients.get("kept").unwrap(),
+            updated.get("kept").unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            clients.get("changed").unwrap(),
+            updated.get("changed").unwrap()
+        ));
+    }
+
+    #[test]
+    fn response_overrides_from_query() {
+        let mut query = BTreeMap::new();
+        query.insert("access_token".to_string(), "token".to_string());
+        query.insert(
+            "response-content-disposition".to_string(),
+            "attachment; filename=\"report.pdf\"".to_string(),
+        );
+
+        let overrides = ResponseOverrides::from_query(query.clone()).expect("valid overrides");
+        assert_eq!(
+            overrides.iter().collect::<Vec<_>>(),
+            vec![(
+                "response-content-disposition",
+                "attachment; filename=\"report.pdf\""
+            )]
+        );
+
+        query.insert("response-x-amz-meta".to_string(), "value".to_string());
+        assert!(ResponseOverrides::from_query(query).is_err());
+
+        let mut params = BTreeMap::new();
+        params.insert(
+            "response-content-type".to_string(),
+            "text/plain\r\nx-injected: 1".to_string(),
+        );
+        assert!(ResponseOverrides::new(params).is_err());
+    }
+
+    #[test]
+    fn object_attributes() {
+        let account = AccountId::new("user", "usr.example.org");
+        let mut metadata = BTreeMap::new();
+        metadata.insert("course-id".to_string(), "42".to_string());
+        let mut tags = BTreeMap::new();
+        tags.insert("retention".to_string(), "1 year".to_string());
+        tags.insert("owner".to_string(), "a&b".to_string());
+
+        let attributes = ObjectAttributes::new(&metadata, &tags, &account).expect("attributes");
+        assert_eq!(
+            attributes.iter().collect::<Vec<_>>(),
+            vec![
+                ("x-amz-meta-account-id", "user.usr.example.org"),
+                ("x-amz-meta-course-id", "42"),
+                ("x-amz-tagging", "owner=a%26b&retention=1%20year"),
+            ]
+        );
+
+        metadata.insert(
+            "account-id".to_string(),
+            "admin.usr.example.org".to_string(),
+        );
+        assert!(ObjectAttributes::new(&metadata, &tags, &account).is_err());
+
+        let mut metadata = BTreeMap::new();
+        metadata.insert("Course Id".to_string(), "42".to_string());
+        assert!(ObjectAttributes::new(&metadata, &tags, &account).is_err());
+
+        let tags = (0..11)
+            .map(|i| (i.to_string(), String::new()))
+            .collect::<BTreeMap<_, _>>();
+        assert!(ObjectAttributes::new(&BTreeMap::new(), &tags, &account).is_err());
     }
 }
@@ -0,0 +1,633 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use rusoto_core::credential::{
+    AwsCredentials, CredentialsError, ProfileProvider, ProvideAwsCredentials,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+use xml::reader::{EventReader, XmlEvent};
+
+/// Credentials are refreshed ahead of their expiration by this margin.
+const EXPIRATION_MARGIN: i64 = 300;
+const STS_ENDPOINT: &str = "https://sts.amazonaws.com";
+const STS_TIMEOUT: Duration = Duration::from_secs(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A value hidden from the debug output of the config.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "**********")
+    }
+}
+
+/// Source of credentials of a backend.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CredentialsConfig {
+    /// `{BACKEND}_AWS_ACCESS_KEY_ID` and `{BACKEND}_AWS_SECRET_ACCESS_KEY`
+    /// environment variables read on start.
+    #[default]
+    Env,
+    /// Values of the config file.
+    Static {
+        access_key_id: String,
+        secret_access_key: Secret,
+        session_token: Option<Secret>,
+    },
+    /// A profile of the shared credentials file.
+    Profile {
+        path: PathBuf,
+        profile: Option<String>,
+        #[serde(
+            default = "default_refresh_interval",
+            deserialize_with = "crate::serde::duration"
+        )]
+        refresh_interval: Duration,
+    },
+    /// A JSON file with `AccessKeyId`, `SecretAccessKey`, `SessionToken`
+    /// and `Expiration` keys, the format used by STS and `credential_process`.
+    TokenFile {
+        path: PathBuf,
+        #[serde(
+            default = "default_refresh_interval",
+            deserialize_with = "crate::serde::duration"
+        )]
+        refresh_interval: Duration,
+    },
+    /// Temporary credentials retrieved with `AssumeRoleWithWebIdentity`
+    /// in exchange for a token of the file.
+    WebIdentity {
+        role_arn: String,
+        token_file: PathBuf,
+        session_name: Option<String>,
+        sts_endpoint: Option<String>,
+    },
+}
+
+fn default_refresh_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum Source {
+    Static(AwsCredentials),
+    Profile(ProfileProvider),
+    TokenFile(PathBuf),
+    WebIdentity {
+        role_arn: String,
+        token_file: PathBuf,
+        session_name: String,
+        sts_endpoint: String,
+        client: reqwest::Client,
+    },
+}
+
+struct Cached {
+    credentials: AwsCredentials,
+    fetched_at: Instant,
+}
+
+/// Provides credentials of a backend re-reading them from their source
+/// on expiration or once in the refresh interval.
+pub struct CredentialsProvider {
+    source: Source,
+    refresh_interval: Option<Duration>,
+    cache: Mutex<Option<Cached>>,
+    /// Serializes refreshes so that concurrent requests wait for a single fetch.
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl CredentialsProvider {
+    pub fn new_static(key: &str, secret: &str, token: Option<String>) -> Self {
+        Self {
+            source: Source::Static(AwsCredentials::new(key, secret, token, None)),
+            refresh_interval: None,
+            cache: Mutex::new(None),
+            refresh: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn from_config(config: &CredentialsConfig, prefix: &str) -> Result<Self> {
+        use std::env::var;
+
+        let (source, refresh_interval) = match config {
+            CredentialsConfig::Env => {
+                let key = var(format!("{}AWS_ACCESS_KEY_ID", prefix))
+                    .with_context(|| format!("{}AWS_ACCESS_KEY_ID must be specified", prefix))?;
+                let secret =
+                    var(format!("{}AWS_SECRET_ACCESS_KEY", prefix)).with_context(|| {
+                        format!("{}AWS_SECRET_ACCESS_KEY must be specified", prefix)
+                    })?;
+
+                return Ok(Self::new_static(&key, &secret, None));
+            }
+            CredentialsConfig::Static {
+                access_key_id,
+                secret_access_key,
+                session_token,
+            } => {
+                return Ok(Self::new_static(
+                    access_key_id,
+                    &secret_access_key.0,
+                    session_token.as_ref().map(|t| t.0.clone()),
+                ));
+            }
+            CredentialsConfig::Profile {
+                path,
+                profile,
+                refresh_interval,
+            } => {
+                let profile = profile.as_deref().unwrap_or("default");
+                (
+                    Source::Profile(ProfileProvider::with_configuration(path, profile)),
+                    Some(*refresh_interval),
+                )
+            }
+            CredentialsConfig::TokenFile {
+                path,
+                refresh_interval,
+            } => (Source::TokenFile(path.to_owned()), Some(*refresh_interval)),
+            CredentialsConfig::WebIdentity {
+                role_arn,
+                token_file,
+                session_name,
+                sts_endpoint,
+            } => {
+                let client = reqwest::Client::builder()
+                    .timeout(STS_TIMEOUT)
+                    .build()
+                    .context("failed to create sts http client")?;
+
+                let source = Source::WebIdentity {
+                    role_arn: role_arn.to_owned(),
+                    token_file: token_file.to_owned(),
+                    session_name: session_name
+                        .to_owned()
+                        .unwrap_or_else(|| "storage".to_owned()),
+                    sts_endpoint: sts_endpoint
+                        .to_owned()
+                        .unwrap_or_else(|| STS_ENDPOINT.to_owned()),
+                    client,
+                };
+                (source, None)
+            }
+        };
+
+        Ok(Self {
+            source,
+            refresh_interval,
+            cache: Mutex::new(None),
+            refresh: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    async fn fetch(&self) -> Result<AwsCredentials> {
+        match self.source {
+            Source::Static(ref credentials) => Ok(credentials.clone()),
+            Source::Profile(ref provider) => provider
+                .credentials()
+                .await
+                .map_err(|err| anyhow!("failed to read credentials profile: {}", err)),
+            Source::TokenFile(ref path) => read_token_file(path),
+            Source::WebIdentity {
+                ref role_arn,
+                ref token_file,
+                ref session_name,
+                ref sts_endpoint,
+                ref client,
+            } => {
+                let token = std::fs::read_to_string(token_file).with_context(|| {
+                    format!("failed to read web identity token file {:?}", token_file)
+                })?;
+
+                assume_role_with_web_identity(
+                    client,
+                    sts_endpoint,
+                    role_arn,
+                    session_name,
+                    token.trim(),
+                )
+                .await
+            }
+        }
+    }
+
+    fn is_fresh(&self, cached: &Cached) -> bool {
+        let expired = cached.credentials.expires_at().is_some_and(|expires_at| {
+            expires_at - chrono::Duration::seconds(EXPIRATION_MARGIN) <= Utc::now()
+        });
+        let outdated = self
+            .refresh_interval
+            .is_some_and(|interval| cached.fetched_at.elapsed() >= interval);
+
+        !expired && !outdated
+    }
+
+    fn cached(&self) -> Option<AwsCredentials> {
+        let cache = self.cache.lock().expect("credentials cache poisoned");
+        cache
+            .as_ref()
+            .filter(|cached| self.is_fresh(cached))
+            .map(|cached| cached.credentials.clone())
+    }
+}
+
+/// Clamps expiration time of a signature to the lifetime of temporary credentials
+/// since a signature isn't accepted once credentials it's made with expire.
+pub fn clamp_to_credentials(credentials: &AwsCredentials, expires_in: Duration) -> Duration {
+    match credentials.expires_at() {
+        Some(expires_at) => {
+            let lifetime = (*expires_at - Utc::now()).to_std().unwrap_or_default();
+            expires_in.min(lifetime)
+        }
+        None => expires_in,
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialsProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if let Source::Static(ref credentials) = self.source {
+            return Ok(credentials.clone());
+        }
+
+        if let Some(credentials) = self.cached() {
+            return Ok(credentials);
+        }
+
+        // Another request may have refreshed credentials while this one was waiting.
+        let _refresh = self.refresh.lock().await;
+        if let Some(credentials) = self.cached() {
+            return Ok(credentials);
+        }
+
+        let stale = self
+            .cache
+            .lock()
+            .expect("credentials cache poisoned")
+            .as_ref()
+            .map(|cached| cached.credentials.clone());
+
+        match self.fetch().await {
+            Ok(credentials) => {
+                info!("backend credentials have been refreshed");
+
+                let mut cache = self.cache.lock().expect("credentials cache poisoned");
+                *cache = Some(Cached {
+                    credentials: credentials.clone(),
+                    fetched_at: Instant::now(),
+                });
+                Ok(credentials)
+            }
+            // Keep using previous credentials until they actually expire.
+            Err(err) => match stale {
+                Some(credentials)
+                    if !credentials
+                        .expires_at()
+                        .is_some_and(|expires_at| expires_at <= Utc::now()) =>
+                {
+                    error!("failed to refresh backend credentials: {:#}", err);
+                    Ok(credentials)
+                }
+                _ => Err(CredentialsError::new(format!("{:#}", err))),
+            },
+        }
+    }
+}
+
+impl fmt::Debug for CredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            Source::Static(ref credentials) => {
+                format!("static({})", credentials.aws_access_key_id())
+            }
+            Source::Profile(ref provider) => {
+                format!(
+                    "profile({:?}, {})",
+                    provider.file_path(),
+                    provider.profile()
+                )
+            }
+            Source::TokenFile(ref path) => format!("token_file({:?})", path),
+            Source::WebIdentity { ref role_arn, .. } => format!("web_identity({})", role_arn),
+        };
+
+        f.debug_struct("CredentialsProvider")
+            .field("source", &source)
+            .field("refresh_interval", &self.refresh_interval)
+            .finish()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn read_token_file(path: &Path) -> Result<AwsCredentials> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read credentials file {:?}", path))?;
+    serde_json::from_slice::<AwsCredentials>(&data)
+        .with_context(|| format!("failed to parse credentials file {:?}", path))
+}
+
+async fn assume_role_with_web_identity(
+    client: &reqwest::Client,
+    sts_endpoint: &str,
+    role_arn: &str,
+    session_name: &str,
+    token: &str,
+) -> Result<AwsCredentials> {
+    let resp = client
+        .post(sts_endpoint)
+        .form(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("Version", "2011-06-15"),
+            ("RoleArn", role_arn),
+            ("RoleSessionName", session_name),
+            ("WebIdentityToken", token),
+        ])
+        .send()
+        .await
+        .context("failed to send AssumeRoleWithWebIdentity request")?;
+
+    let status = resp.status();
+    let body = resp
+        .text()
+        .await
+        .context("failed to read AssumeRoleWithWebIdentity response")?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "AssumeRoleWithWebIdentity failed with {}: {}",
+            status,
+            body
+        ));
+    }
+
+    parse_sts_credentials(&body)
+}
+
+/// Parses `Credentials` element of an STS response.
+fn parse_sts_credentials(body: &str) -> Result<AwsCredentials> {
+    let mut key = None;
+    let mut secret = None;
+    let mut token = None;
+    let mut expiration = None;
+    let mut element = None;
+
+    for event in EventReader::from_str(body) {
+        match event.context("failed to parse sts response")? {
+            XmlEvent::StartElement { name, .. } => element = Some(name.local_name),
+            XmlEvent::Characters(value) => match element.as_deref() {
+                Some("AccessKeyId") => key = Some(value),
+                Some("SecretAccessKey") => secret = Some(value),
+                Some("SessionToken") => token = Some(value),
+                Some("Expiration") => expiration = Some(value),
+                _ => {}
+            },
+            XmlEvent::EndElement { .. } => element = None,
+            _ => {}
+        }
+    }
+
+    let expires_at = expiration
+        .map(|value| chrono::DateTime::parse_from_rfc3339(&value))
+        .transpose()
+        .context("invalid expiration of sts credentials")?
+        .map(|value| value.with_timezone(&Utc));
+
+    Ok(AwsCredentials::new(
+        key.ok_or_else(|| anyhow!("missing AccessKeyId in sts response"))?,
+        secret.ok_or_else(|| anyhow!("missing SecretAccessKey in sts response"))?,
+        token,
+        expires_at,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn sts_response(expires_at: chrono::DateTime<Utc>) -> String {
+        format!(
+            r#"<AssumeRoleWithWebIdentityResponse>
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <AccessKeyId>key</AccessKeyId>
+      <SecretAccessKey>secret</SecretAccessKey>
+      <SessionToken>session-token</SessionToken>
+      <Expiration>{}</Expiration>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#,
+            expires_at.to_rfc3339()
+        )
+    }
+
+    /// An STS endpoint answering requests with `responses` in order
+    /// (the last one repeats) after a delay, counting the requests.
+    fn serve_sts(responses: Vec<(&'static str, String)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("address");
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut req = Vec::new();
+                let mut buf = [0; 4096];
+                // Read the headers and the form body.
+                loop {
+                    let len = stream.read(&mut buf).unwrap_or_default();
+                    req.extend_from_slice(&buf[..len]);
+                    let data = String::from_utf8_lossy(&req);
+                    let complete = data.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().ok())?
+                            })
+                            .unwrap_or_default();
+                        body.len() >= length
+                    });
+                    if len == 0 || complete {
+                        break;
+                    }
+                }
+
+                let idx = counter.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = &responses[idx.min(responses.len() - 1)];
+                std::thread::sleep(Duration::from_millis(100));
+                let resp = format!(
+                    "HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+        });
+        (format!("http://{}", addr), count)
+    }
+
+    fn web_identity(name: &str, sts_endpoint: String) -> CredentialsProvider {
+        let token_file =
+            std::env::temp_dir().join(format!("storage-{}-{}.token", name, std::process::id()));
+        std::fs::write(&token_file, "token").expect("token file");
+
+        let config = CredentialsConfig::WebIdentity {
+            role_arn: "arn:aws:iam::123456789012:role/storage".to_owned(),
+            token_file,
+            session_name: None,
+            sts_endpoint: Some(sts_endpoint),
+        };
+        CredentialsProvider::from_config(&config, "").expect("provider")
+    }
+
+    #[tokio::test]
+    async fn refresh_once_for_concurrent_requests() {
+        let expires_at = Utc::now() + chrono::Duration::seconds(3600);
+        let (endpoint, count) = serve_sts(vec![("200 OK", sts_response(expires_at))]);
+        let provider = Arc::new(web_identity("concurrent", endpoint));
+
+        let requests = (0..8).map(|_| {
+            let provider = provider.clone();
+            tokio::spawn(async move { provider.credentials().await })
+        });
+        for result in futures::future::join_all(requests).await {
+            let credentials = result.expect("task").expect("credentials");
+            assert_eq!(credentials.aws_access_key_id(), "key");
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Fresh credentials are served from the cache.
+        provider.credentials().await.expect("credentials");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_stale_credentials() {
+        // Credentials within the expiration margin are refreshed on each request.
+        let expires_at = Utc::now() + chrono::Duration::seconds(EXPIRATION_MARGIN / 2);
+        let (endpoint, count) = serve_sts(vec![
+            ("200 OK", sts_response(expires_at)),
+            ("500 Internal Server Error", String::new()),
+        ]);
+        let provider = web_identity("stale", endpoint);
+
+        let credentials = provider.credentials().await.expect("credentials");
+        let stale = provider.credentials().await.expect("stale credentials");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(stale.token(), credentials.token());
+        assert_eq!(stale.expires_at(), credentials.expires_at());
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_without_credentials() {
+        let (endpoint, _) = serve_sts(vec![("500 Internal Server Error", String::new())]);
+        let provider = web_identity("failed", endpoint);
+
+        assert!(provider.credentials().await.is_err());
+    }
+
+    #[test]
+    fn parse_sts_credentials_errors() {
+        assert!(parse_sts_credentials("<Credentials>").is_err());
+        assert!(parse_sts_credentials(
+            "<Credentials><SecretAccessKey>secret</SecretAccessKey></Credentials>"
+        )
+        .is_err());
+        assert!(parse_sts_credentials(
+            "<Credentials><AccessKeyId>key</AccessKeyId><SecretAccessKey>secret</SecretAccessKey><Expiration>tomorrow</Expiration></Credentials>"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_sts_credentials_test() {
+        let body = r#"<AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <SessionToken>session-token</SessionToken>
+      <SecretAccessKey>secret</SecretAccessKey>
+      <Expiration>2023-06-01T12:00:00Z</Expiration>
+      <AccessKeyId>key</AccessKeyId>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#;
+
+        let credentials = parse_sts_credentials(body).expect("credentials");
+        assert_eq!(credentials.aws_access_key_id(), "key");
+        assert_eq!(credentials.aws_secret_access_key(), "secret");
+        assert_eq!(credentials.token().as_deref(), Some("session-token"));
+        assert_eq!(
+            credentials.expires_at().map(|e| e.to_rfc3339()),
+            Some("2023-06-01T12:00:00+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn clamp_to_credentials_test() {
+        let expires_in = Duration::from_secs(86400);
+
+        let credentials = AwsCredentials::new("key", "secret", None, None);
+        assert_eq!(clamp_to_credentials(&credentials, expires_in), expires_in);
+
+        let expires_at = Utc::now() + chrono::Duration::seconds(3600);
+        let credentials = AwsCredentials::new("key", "secret", None, Some(expires_at));
+        let clamped = clamp_to_credentials(&credentials, expires_in);
+        assert!(clamped <= Duration::from_secs(3600) && clamped > Duration::from_secs(3500));
+
+        let expires_at = Utc::now() - chrono::Duration::seconds(60);
+        let credentials = AwsCredentials::new("key", "secret", None, Some(expires_at));
+        assert_eq!(
+            clamp_to_credentials(&credentials, expires_in),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn credentials_config_test() {
+        let config: CredentialsConfig = serde_json::from_str(
+            r#"{"type": "token_file", "path": "/var/run/secrets/s3.json", "refresh_interval": 30}"#,
+        )
+        .expect("config");
+
+        match config {
+            CredentialsConfig::TokenFile {
+                path,
+                refresh_interval,
+            } => {
+                assert_eq!(path, PathBuf::from("/var/run/secrets/s3.json"));
+                assert_eq!(refresh_interval, Duration::from_secs(30));
+            }
+            _ => panic!("unexpected config = {:?}", config),
+        }
+    }
+}
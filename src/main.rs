@@ -2,6 +2,7 @@ use crate::app::{config::AppConfig, http};
 use ::tracing::warn;
 
 mod app;
+mod credentials;
 mod s3;
 mod serde;
 mod tracing;
@@ -1,28 +1,180 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::atomic::{AtomicUsize, Ordering},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use anyhow::{Context, Result};
-use rusoto_core::{credential::AwsCredentials, signature::SignedRequest, Region};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rusoto_core::{
+    credential::{AwsCredentials, ProvideAwsCredentials},
+    signature::SignedRequest,
+    HttpClient, Region, RusotoError,
+};
+use rusoto_s3::{
+    AbortMultipartUploadError, AbortMultipartUploadRequest, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest,
+    DeleteObjectRequest, HeadObjectError, HeadObjectOutput, HeadObjectRequest, ListObjectsV2Error,
+    ListObjectsV2Request, S3Client, UploadPartCopyRequest, S3,
+};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use svc_authn::AccountId;
+use tracing::{error, warn};
 use url::Url;
 
-use crate::app::util::ProxyHost;
+use crate::{
+    app::{
+        config::ReadMode,
+        metrics::METRICS,
+        util::{
+            AddressingStyle, CdnConfig, ProxyHealthCheck, ProxyHost, ProxyStrategy,
+            ResponseOverrides, SignatureVersion,
+        },
+    },
+    credentials::{clamp_to_credentials, CredentialsProvider},
+};
+
+use self::cdn::CdnSigner;
+use self::presign::{is_query_attribute, signature_v2, Presigner};
+use self::proxy::{Affinity, ProxyHosts, Selection};
+pub use self::proxy::{Location, RouteHosts};
+
+mod cdn;
+mod presign;
+mod proxy;
+
+/// The largest object copied with a single `CopyObject` request.
+const MAX_COPY_OBJECT_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+/// A part size of larger objects, it fits the largest object in 10000 parts.
+const COPY_PART_SIZE: i64 = 1024 * 1024 * 1024;
+const COPY_PART_CONCURRENCY: usize = 4;
+
+/// Characters of an object key escaped in the `x-amz-copy-source` header.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
 
-#[derive(Debug)]
 pub struct Client {
-    credentials: AwsCredentials,
+    name: String,
+    credentials: Arc<CredentialsProvider>,
     region: Region,
     expires_in: Duration,
-    proxy_hosts: Option<BTreeMap<String, Vec<String>>>,
-    counter: AtomicUsize,
+    proxy_hosts: ProxyHosts,
+    proxy_health_check: ProxyHealthCheck,
+    /// Set once health checks of proxy hosts are running, the client
+    /// is reused by contexts built on config reload.
+    proxy_health_checks_spawned: AtomicBool,
+    read_mode: ReadMode,
+    cdn: Option<CdnSigner>,
+    presigner: Presigner,
+    fallbacks: Vec<String>,
+    api: S3Client,
+    http: reqwest::Client,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("name", &self.name)
+            .field("credentials", &self.credentials)
+            .field("region", &self.region)
+            .field("expires_in", &self.expires_in)
+            .field("proxy_hosts", &self.proxy_hosts)
+            .field("proxy_health_check", &self.proxy_health_check)
+            .field("read_mode", &self.read_mode)
+            .field("cdn", &self.cdn)
+            .field("presigner", &self.presigner)
+            .field("fallbacks", &self.fallbacks)
+            .finish()
+    }
+}
+
+/// An error of a request performed by the backend on behalf of a client.
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+/// An object stored on the backend.
+#[derive(Debug, Serialize)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: Option<i64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Metadata of an object stored on the backend.
+#[derive(Debug, Serialize)]
+pub struct ObjectMetadata {
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A page of objects stored on the backend.
+#[derive(Debug, Serialize)]
+pub struct ObjectList {
+    pub objects: Vec<ObjectInfo>,
+    pub next_continuation_token: Option<String>,
+}
+
+/// Restrictions of a browser-based upload.
+#[derive(Debug, Default)]
+pub struct PostPolicyConditions {
+    pub content_type: Option<String>,
+    pub content_type_prefix: Option<String>,
+    pub content_length_range: Option<(u64, u64)>,
+    /// Exact `x-amz-meta-*` metadata of the object without the prefix.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A URL and form fields of a browser-based upload.
+#[derive(Debug, Serialize)]
+pub struct PresignedPost {
+    pub url: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(err) => write!(f, "not found: {}", err),
+            Self::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<RusotoError<E>> for ApiError {
+    fn from(err: RusotoError<E>) -> Self {
+        match err {
+            RusotoError::Unknown(ref resp) if resp.status == http::StatusCode::NOT_FOUND => {
+                Self::NotFound(anyhow::Error::new(err))
+            }
+            err => Self::Other(anyhow::Error::new(err)),
+        }
+    }
 }
 
 impl Client {
     pub fn new(
-        key: &str,
-        secret: &str,
+        name: &str,
+        credentials: CredentialsProvider,
         region: &str,
         endpoint: &str,
         expires_in: Duration,
@@ -31,90 +183,693 @@ impl Client {
             name: region.to_string(),
             endpoint: endpoint.to_string(),
         };
-        let credentials = AwsCredentials::new(key, secret, None, None);
+        let credentials = Arc::new(credentials);
+        let api = S3Client::new_with(
+            HttpClient::new().expect("failed to create s3 http client"),
+            credentials.clone(),
+            region.clone(),
+        );
 
         Self {
+            name: name.to_owned(),
             credentials,
             region,
             expires_in,
-            proxy_hosts: None,
-            counter: AtomicUsize::new(0),
+            proxy_hosts: ProxyHosts::default(),
+            proxy_health_check: ProxyHealthCheck::default(),
+            proxy_health_checks_spawned: AtomicBool::new(false),
+            read_mode: ReadMode::default(),
+            cdn: None,
+            presigner: Presigner::default(),
+            fallbacks: vec![],
+            api,
+            http: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .build()
+                .expect("failed to create s3 streaming http client"),
         }
     }
 
-    pub fn set_proxy_hosts(&mut self, proxy_hosts: &HashMap<String, Vec<ProxyHost>>) -> &mut Self {
-        let mut resulting_hosts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_proxy_hosts(
+        &mut self,
+        proxy_hosts: &HashMap<String, Vec<ProxyHost>>,
+        strategy: ProxyStrategy,
+    ) -> &mut Self {
+        self.proxy_hosts = ProxyHosts::new(proxy_hosts, strategy);
+        self
+    }
 
-        for (country, hosts) in proxy_hosts {
-            for host in hosts {
-                let mut _hosts = host
-                    .alias_range_upper_bound
-                    .map(|upper_bound| {
-                        (1..=upper_bound)
-                            .map(|idx| format!("{idx}.{}", host.base))
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or(vec![host.base.to_owned()]);
+    pub fn set_cdn(&mut self, cdn: &CdnConfig) -> &mut Self {
+        self.cdn = Some(CdnSigner::new(cdn));
+        self
+    }
 
-                resulting_hosts
-                    .entry(country.as_str().to_lowercase())
-                    .and_modify(|h| h.append(_hosts.as_mut()))
-                    .or_insert(_hosts);
-            }
-        }
+    pub fn set_signing(&mut self, version: SignatureVersion, style: AddressingStyle) -> &mut Self {
+        self.presigner = Presigner { version, style };
+        self
+    }
+
+    /// Headers of signed URLs a client has to send along with them,
+    /// object attributes are signed as query parameters with Signature Version 4.
+    pub fn required_header(&self, header: &str) -> bool {
+        self.presigner.version == SignatureVersion::V2 || !is_query_attribute(header)
+    }
+
+    pub fn set_fallbacks(&mut self, fallbacks: &[String]) -> &mut Self {
+        self.fallbacks = fallbacks.to_owned();
+        self
+    }
+
+    /// Names of backends holding replicas of objects of the backend.
+    pub fn fallbacks(&self) -> &[String] {
+        &self.fallbacks
+    }
 
-        self.proxy_hosts = Some(resulting_hosts);
+    pub fn set_read_mode(&mut self, read_mode: ReadMode) -> &mut Self {
+        self.read_mode = read_mode;
         self
     }
 
+    pub fn read_mode(&self) -> ReadMode {
+        self.read_mode
+    }
+
+    pub fn set_proxy_health_check(&mut self, health_check: &ProxyHealthCheck) -> &mut Self {
+        self.proxy_health_check = health_check.to_owned();
+        self
+    }
+
+    /// Checks proxy hosts in background until the client is dropped,
+    /// the checks are spawned once per client.
+    pub fn spawn_proxy_health_checks(self: &Arc<Self>) {
+        if self.proxy_hosts.is_empty()
+            || self
+                .proxy_health_checks_spawned
+                .swap(true, Ordering::AcqRel)
+        {
+            return;
+        }
+
+        let client = Arc::downgrade(self);
+        let config = self.proxy_health_check.clone();
+        let scheme = SignedRequest::new("HEAD", "s3", &self.region, "/")
+            .scheme()
+            .to_owned();
+
+        tokio::spawn(async move {
+            let http = match reqwest::Client::builder().timeout(config.timeout).build() {
+                Ok(http) => http,
+                Err(err) => {
+                    error!("failed to create proxy health check http client: {}", err);
+                    return;
+                }
+            };
+
+            let mut interval = tokio::time::interval(config.interval);
+            loop {
+                interval.tick().await;
+
+                let client = match client.upgrade() {
+                    Some(client) => client,
+                    None => return,
+                };
+                client.proxy_hosts.check(&http, &scheme, &config).await;
+                for (host, healthy) in client.proxy_hosts.health() {
+                    METRICS.set_proxy_host_up(&client.name, host, healthy);
+                }
+            }
+        });
+    }
+
+    pub fn expires_in(&self) -> Duration {
+        self.expires_in
+    }
+
     pub fn create_request(&self, method: &str, bucket: &str, object: &str) -> SignedRequest {
         let uri = format!("/{bucket}/{object}", bucket = bucket, object = object);
         SignedRequest::new(method, "s3", &self.region, &uri)
     }
 
-    fn get_proxy_hosts(&self, country: Option<String>) -> Option<&Vec<String>> {
-        country.and_then(|c| self.proxy_hosts.as_ref()?.get(&c.to_lowercase()))
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        self.credentials
+            .credentials()
+            .await
+            .context("failed to get backend credentials")
     }
 
-    pub fn sign_request(&self, req: &mut SignedRequest, country: Option<String>) -> Result<String> {
-        let url = req.generate_presigned_url(&self.credentials, &self.expires_in, false);
+    pub async fn sign_request(
+        &self,
+        req: &mut SignedRequest,
+        location: Location,
+        account: &AccountId,
+        expires_in: &Duration,
+    ) -> Result<String> {
+        let affinity = Affinity {
+            object: req.path().trim_start_matches('/'),
+            account: &account.to_string(),
+        };
+        let selection = self.proxy_hosts.select(&location, affinity);
+        METRICS.inc_signatures(&self.name, req.method());
 
-        if let Some(proxy_hosts) = self.get_proxy_hosts(country) {
-            let mut parsed_url = Url::parse(&url).context("failed to parse generated uri")?;
-            let idx = self.counter.fetch_add(1, Ordering::Acquire) % proxy_hosts.len();
-            let proxy_host = proxy_hosts.get(idx).map(|h| h.as_str());
+        // The CDN token doesn't cover query parameters, URLs with response overrides
+        // are signed for the backend so that they can't be altered.
+        if let (Selection::Cdn(cdn_host), Some(cdn)) = (&selection, &self.cdn) {
+            if matches!(req.method(), "GET" | "HEAD") && req.params.is_empty() {
+                let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+                let url = cdn.sign(cdn_host, req.path(), expires)?;
+                METRICS.inc_proxy_host(location.country.as_deref().unwrap_or_default(), cdn_host);
+                return Ok(url);
+            }
+        }
 
-            parsed_url
-                .set_host(proxy_host)
-                .context("failed to set proxy backend")?;
+        let credentials = self.credentials().await?;
+        let expires_in = clamp_to_credentials(&credentials, *expires_in);
+        let url = self
+            .presigner
+            .presign(req, &credentials, Utc::now(), &expires_in);
+        self.proxy_url(url, &location, selection)
+    }
 
-            Ok(parsed_url.to_string())
-        } else {
-            Ok(url)
+    fn proxy_url(&self, url: String, location: &Location, selection: Selection) -> Result<String> {
+        let country = location.country.as_deref().unwrap_or_default();
+
+        match selection {
+            Selection::Host(proxy_host) => {
+                let mut parsed_url = Url::parse(&url).context("failed to parse generated uri")?;
+                parsed_url
+                    .set_host(Some(proxy_host))
+                    .context("failed to set proxy backend")?;
+                METRICS.inc_proxy_host(country, proxy_host);
+
+                Ok(parsed_url.to_string())
+            }
+            Selection::Unavailable => {
+                warn!(
+                    "all proxy hosts matching the location {:?} are unhealthy, signing a direct url",
+                    location
+                );
+                METRICS.inc_proxy_host(country, "direct");
+                Ok(url)
+            }
+            // CDN hosts only serve reads, other requests go to the backend.
+            Selection::Cdn(_) | Selection::NotConfigured => Ok(url),
+        }
+    }
+
+    /// Proxy hosts signed URLs for the location may be rewritten to.
+    pub fn proxy_routes(&self, location: &Location) -> Vec<RouteHosts<'_>> {
+        self.proxy_hosts.routes(location)
+    }
+
+    pub fn proxy_strategy(&self) -> ProxyStrategy {
+        self.proxy_hosts.strategy()
+    }
+
+    /// Checks the endpoint is reachable and accepts credentials
+    /// with a signed `HEAD` request of the service root.
+    ///
+    /// `403 Forbidden` passes the check since credentials scoped to buckets
+    /// aren't allowed to list buckets of the account.
+    pub async fn check(&self, http: &reqwest::Client) -> Result<()> {
+        let credentials = self.credentials().await?;
+        let req = SignedRequest::new("HEAD", "s3", &self.region, "/");
+        let url = self
+            .presigner
+            .presign(&req, &credentials, Utc::now(), &Duration::from_secs(60));
+
+        let status = http
+            .head(url)
+            .send()
+            .await
+            .context("backend is unreachable")?
+            .status();
+        if status.is_server_error() || status == http::StatusCode::UNAUTHORIZED {
+            bail!("backend responded with {}", status);
         }
+
+        Ok(())
     }
 
-    pub fn presigned_url(
+    /// Generates a URL and form fields of a browser-based upload
+    /// restricted with a signed POST policy.
+    pub async fn presigned_post(
         &self,
-        country: Option<String>,
-        method: &str,
+        location: Location,
+        account: &AccountId,
         bucket: &str,
         object: &str,
-    ) -> Result<String> {
-        self.sign_request(&mut self.create_request(method, bucket, object), country)
+        conditions: &PostPolicyConditions,
+        expires_in: &Duration,
+    ) -> Result<PresignedPost> {
+        let credentials = self.credentials().await?;
+        let expires_in = clamp_to_credentials(&credentials, *expires_in);
+        let mut post = self.presigned_post_at(
+            &credentials,
+            Utc::now(),
+            bucket,
+            object,
+            conditions,
+            &expires_in,
+        )?;
+        METRICS.inc_signatures(&self.name, "POST");
+        let affinity = Affinity {
+            object: &format!("{}/{}", bucket, object),
+            account: &account.to_string(),
+        };
+        let selection = self.proxy_hosts.select(&location, affinity);
+        post.url = self.proxy_url(post.url, &location, selection)?;
+        Ok(post)
+    }
+
+    fn presigned_post_at(
+        &self,
+        credentials: &AwsCredentials,
+        now: DateTime<Utc>,
+        bucket: &str,
+        object: &str,
+        conditions: &PostPolicyConditions,
+        expires_in: &Duration,
+    ) -> Result<PresignedPost> {
+        let req = SignedRequest::new("POST", "s3", &self.region, &format!("/{}", bucket));
+        let url = self.presigner.url(&req);
+
+        let date = now.format("%Y%m%d").to_string();
+        let expiration =
+            now + chrono::Duration::from_std(*expires_in).context("invalid expiration time")?;
+
+        let mut fields = BTreeMap::new();
+        fields.insert("key".to_owned(), object.to_owned());
+        if self.presigner.version == SignatureVersion::V4 {
+            let credential = format!(
+                "{}/{}/{}/s3/aws4_request",
+                credentials.aws_access_key_id(),
+                date,
+                self.region.name()
+            );
+            fields.insert("x-amz-algorithm".to_owned(), "AWS4-HMAC-SHA256".to_owned());
+            fields.insert("x-amz-credential".to_owned(), credential);
+            fields.insert(
+                "x-amz-date".to_owned(),
+                now.format("%Y%m%dT%H%M%SZ").to_string(),
+            );
+        }
+        if let Some(token) = credentials.token() {
+            fields.insert("x-amz-security-token".to_owned(), token.to_owned());
+        }
+        if let Some(ref content_type) = conditions.content_type {
+            fields.insert("Content-Type".to_owned(), content_type.to_owned());
+        }
+        for (key, value) in &conditions.metadata {
+            fields.insert(format!("x-amz-meta-{}", key), value.to_owned());
+        }
+
+        let mut policy_conditions = vec![json!({ "bucket": bucket })];
+        for (key, value) in &fields {
+            policy_conditions.push(json!({ key: value }));
+        }
+        if let Some(ref prefix) = conditions.content_type_prefix {
+            policy_conditions.push(json!(["starts-with", "$Content-Type", prefix]));
+        }
+        if let Some((min, max)) = conditions.content_length_range {
+            policy_conditions.push(json!(["content-length-range", min, max]));
+        }
+
+        let policy = json!({
+            "expiration": expiration.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "conditions": policy_conditions,
+        });
+        let policy = base64::engine::general_purpose::STANDARD.encode(policy.to_string());
+
+        match self.presigner.version {
+            SignatureVersion::V4 => {
+                let key = signing_key(
+                    credentials.aws_secret_access_key(),
+                    &date,
+                    self.region.name(),
+                    "s3",
+                );
+                let signature = hex::encode(hmac_sha256(&key, policy.as_bytes()));
+                fields.insert("x-amz-signature".to_owned(), signature);
+            }
+            SignatureVersion::V2 => {
+                fields.insert(
+                    "AWSAccessKeyId".to_owned(),
+                    credentials.aws_access_key_id().to_owned(),
+                );
+                fields.insert(
+                    "signature".to_owned(),
+                    signature_v2(credentials, policy.as_bytes()),
+                );
+            }
+        }
+        fields.insert("policy".to_owned(), policy);
+
+        Ok(PresignedPost { url, fields })
     }
+
+    pub async fn head_object(
+        &self,
+        bucket: &str,
+        object: &str,
+    ) -> Result<ObjectMetadata, ApiError> {
+        let req = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            ..Default::default()
+        };
+
+        match self.api.head_object(req).await {
+            Ok(output) => Ok(ObjectMetadata {
+                size: output.content_length,
+                content_type: output.content_type,
+                etag: output.e_tag,
+                last_modified: output.last_modified,
+            }),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(msg))) => {
+                Err(ApiError::NotFound(anyhow::anyhow!(msg)))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Requests an object of the backend endpoint directly with a signed URL,
+    /// a response of any status is returned to be passed to the client as is.
+    pub async fn get_object(
+        &self,
+        bucket: &str,
+        object: &str,
+        overrides: &ResponseOverrides,
+        headers: http::HeaderMap,
+    ) -> Result<reqwest::Response> {
+        let credentials = self.credentials().await?;
+        let mut req = self.create_request("GET", bucket, object);
+        for (key, val) in overrides.iter() {
+            req.add_param(key, val);
+        }
+        let url = self
+            .presigner
+            .presign(&req, &credentials, Utc::now(), &Duration::from_secs(60));
+
+        self.http
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .context("backend is unreachable")
+    }
+
+    pub async fn delete_object(&self, bucket: &str, object: &str) -> Result<(), ApiError> {
+        let req = DeleteObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            ..Default::default()
+        };
+
+        self.api.delete_object(req).await?;
+        Ok(())
+    }
+
+    /// Copies an object on the server side, the source and the destination
+    /// may reside in different buckets. Objects larger than `CopyObject` allows
+    /// are copied in parts of a multipart upload.
+    pub async fn copy_object(
+        &self,
+        source_bucket: &str,
+        source_object: &str,
+        bucket: &str,
+        object: &str,
+    ) -> Result<Option<String>, ApiError> {
+        let copy_source = format!(
+            "{}/{}",
+            source_bucket,
+            utf8_percent_encode(source_object, COPY_SOURCE)
+        );
+
+        let req = HeadObjectRequest {
+            bucket: source_bucket.to_owned(),
+            key: source_object.to_owned(),
+            ..Default::default()
+        };
+        let source = match self.api.head_object(req).await {
+            Ok(output) => output,
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(msg))) => {
+                return Err(ApiError::NotFound(anyhow::anyhow!(msg)))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let size = source.content_length.unwrap_or_default();
+        if size > MAX_COPY_OBJECT_SIZE {
+            return self
+                .copy_object_multipart(&copy_source, size, source, bucket, object)
+                .await;
+        }
+
+        let req = CopyObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            copy_source,
+            ..Default::default()
+        };
+
+        let output = self.api.copy_object(req).await?;
+        Ok(output.copy_object_result.and_then(|result| result.e_tag))
+    }
+
+    async fn copy_object_multipart(
+        &self,
+        copy_source: &str,
+        size: i64,
+        source: HeadObjectOutput,
+        bucket: &str,
+        object: &str,
+    ) -> Result<Option<String>, ApiError> {
+        let req = CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            cache_control: source.cache_control,
+            content_disposition: source.content_disposition,
+            content_encoding: source.content_encoding,
+            content_language: source.content_language,
+            content_type: source.content_type,
+            metadata: source.metadata,
+            ..Default::default()
+        };
+        let upload_id = self
+            .api
+            .create_multipart_upload(req)
+            .await?
+            .upload_id
+            .ok_or_else(|| ApiError::Other(anyhow::anyhow!("missing upload id in the response")))?;
+
+        let parts = stream::iter(copy_part_ranges(size).enumerate())
+            .map(|(idx, (start, end))| {
+                let req = UploadPartCopyRequest {
+                    bucket: bucket.to_owned(),
+                    key: object.to_owned(),
+                    copy_source: copy_source.to_owned(),
+                    copy_source_range: Some(format!("bytes={}-{}", start, end)),
+                    part_number: idx as i64 + 1,
+                    upload_id: upload_id.clone(),
+                    ..Default::default()
+                };
+                async move {
+                    let output = self.api.upload_part_copy(req).await?;
+                    let etag = output
+                        .copy_part_result
+                        .and_then(|result| result.e_tag)
+                        .ok_or_else(|| {
+                            ApiError::Other(anyhow::anyhow!("missing etag of part {}", idx + 1))
+                        })?;
+                    Ok::<_, ApiError>((idx as i64 + 1, etag))
+                }
+            })
+            .buffered(COPY_PART_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await;
+
+        let result = match parts {
+            Ok(parts) => {
+                self.complete_multipart_upload(bucket, object, &upload_id, parts)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            if let Err(err) = self
+                .abort_multipart_upload(bucket, object, &upload_id)
+                .await
+            {
+                warn!("failed to abort a multipart copy {}: {}", upload_id, err);
+            }
+        }
+        result
+    }
+
+    /// Lists objects with the prefix stripping it from their names.
+    pub async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+        max_keys: Option<i64>,
+    ) -> Result<ObjectList, ApiError> {
+        let req = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token,
+            max_keys,
+            ..Default::default()
+        };
+
+        let output = match self.api.list_objects_v2(req).await {
+            Ok(output) => output,
+            Err(RusotoError::Service(ListObjectsV2Error::NoSuchBucket(msg))) => {
+                return Err(ApiError::NotFound(anyhow::anyhow!(msg)))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let objects = output
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| {
+                let name = object.key?.strip_prefix(prefix)?.to_owned();
+                Some(ObjectInfo {
+                    name,
+                    size: object.size,
+                    etag: object.e_tag,
+                    last_modified: object.last_modified,
+                })
+            })
+            .collect();
+
+        Ok(ObjectList {
+            objects,
+            next_continuation_token: output.next_continuation_token,
+        })
+    }
+
+    pub async fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        object: &str,
+        content_type: Option<String>,
+        metadata: HashMap<String, String>,
+    ) -> Result<String, ApiError> {
+        let req = CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            content_type,
+            metadata: Some(metadata),
+            ..Default::default()
+        };
+
+        self.api
+            .create_multipart_upload(req)
+            .await?
+            .upload_id
+            .ok_or_else(|| ApiError::Other(anyhow::anyhow!("missing upload id in the response")))
+    }
+
+    pub async fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        object: &str,
+        upload_id: &str,
+        parts: Vec<(i64, String)>,
+    ) -> Result<Option<String>, ApiError> {
+        let parts = parts
+            .into_iter()
+            .map(|(part_number, etag)| CompletedPart {
+                e_tag: Some(etag),
+                part_number: Some(part_number),
+            })
+            .collect();
+
+        let req = CompleteMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            upload_id: upload_id.to_owned(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+
+        Ok(self.api.complete_multipart_upload(req).await?.e_tag)
+    }
+
+    pub async fn abort_multipart_upload(
+        &self,
+        bucket: &str,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<(), ApiError> {
+        let req = AbortMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            upload_id: upload_id.to_owned(),
+            ..Default::default()
+        };
+
+        match self.api.abort_multipart_upload(req).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(AbortMultipartUploadError::NoSuchUpload(msg))) => {
+                Err(ApiError::NotFound(anyhow::anyhow!(msg)))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Byte ranges of parts an object of the size is copied in, bounds are inclusive.
+fn copy_part_ranges(size: i64) -> impl Iterator<Item = (i64, i64)> {
+    (0..size)
+        .step_by(COPY_PART_SIZE as usize)
+        .map(move |start| (start, (start + COPY_PART_SIZE).min(size) - 1))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Derives a key of AWS Signature Version 4.
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{app::util::ProxyHost, s3::Client};
-    use std::collections::{BTreeMap, HashMap};
+    use crate::{
+        app::util::{AddressingStyle, ProxyHost, ProxyKind, ProxyStrategy, SignatureVersion},
+        credentials::CredentialsProvider,
+        s3::{
+            copy_part_ranges, signature_v2, signing_key, Client, PostPolicyConditions,
+            COPY_PART_SIZE,
+        },
+    };
+    use base64::Engine;
+    use chrono::{TimeZone, Utc};
+    use rusoto_core::credential::AwsCredentials;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::Duration,
+    };
 
     #[test]
     fn set_proxy_hosts_test() {
         let mut client = Client::new(
-            "key",
-            "secret",
+            "test",
+            CredentialsProvider::new_static("key", "secret", None),
             "region",
             "endpoint",
             ::std::time::Duration::from_secs(300),
@@ -125,10 +880,14 @@ mod tests {
             ProxyHost {
                 base: "ua1.example.org".to_string(),
                 alias_range_upper_bound: None,
+                weight: 1,
+                kind: ProxyKind::Proxy,
             },
             ProxyHost {
                 base: "ua2.example.org".to_string(),
                 alias_range_upper_bound: Some(2),
+                weight: 1,
+                kind: ProxyKind::Proxy,
             },
         ];
         hosts.insert("ua".to_string(), ua_hosts);
@@ -136,10 +895,12 @@ mod tests {
         let es_host = ProxyHost {
             base: "es.example.org".to_string(),
             alias_range_upper_bound: None,
+            weight: 1,
+            kind: ProxyKind::Proxy,
         };
         hosts.insert("es".to_string(), vec![es_host]);
 
-        let result = client.set_proxy_hosts(&hosts);
+        let result = client.set_proxy_hosts(&hosts, ProxyStrategy::RoundRobin);
 
         let mut expected = BTreeMap::new();
         expected.insert(
@@ -152,6 +913,130 @@ mod tests {
         );
         expected.insert("es".to_string(), vec!["es.example.org".to_string()]);
 
-        assert_eq!(result.proxy_hosts, Some(expected));
+        assert_eq!(result.proxy_hosts.hosts(), expected);
+    }
+
+    #[test]
+    fn signing_key_test() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn presigned_post_test() {
+        let client = Client::new(
+            "test",
+            CredentialsProvider::new_static("key", "secret", None),
+            "region",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        );
+
+        let conditions = PostPolicyConditions {
+            content_type: Some("video/mp4".to_string()),
+            content_type_prefix: None,
+            content_length_range: Some((1, 1024)),
+            metadata: std::iter::once((
+                "account-id".to_string(),
+                "user.usr.example.org".to_string(),
+            ))
+            .collect(),
+        };
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let post = client
+            .presigned_post_at(
+                &AwsCredentials::new("key", "secret", None, None),
+                now,
+                "example.org",
+                "foo.bar",
+                &conditions,
+                &Duration::from_secs(300),
+            )
+            .expect("presigned post");
+
+        assert_eq!(post.url, "https://s3.example.org/example.org");
+        assert_eq!(post.fields["key"], "foo.bar");
+        assert_eq!(post.fields["Content-Type"], "video/mp4");
+        assert_eq!(
+            post.fields["x-amz-credential"],
+            "key/20230601/region/s3/aws4_request"
+        );
+        assert_eq!(post.fields["x-amz-date"], "20230601T120000Z");
+        assert_eq!(post.fields["x-amz-signature"].len(), 64);
+
+        let policy = base64::engine::general_purpose::STANDARD
+            .decode(&post.fields["policy"])
+            .expect("base64 policy");
+        let policy: serde_json::Value = serde_json::from_slice(&policy).expect("json policy");
+
+        assert_eq!(policy["expiration"], "2023-06-01T12:05:00.000Z");
+        let conditions = policy["conditions"].as_array().expect("conditions");
+        assert!(conditions.contains(&serde_json::json!({ "bucket": "example.org" })));
+        assert!(conditions.contains(&serde_json::json!({ "key": "foo.bar" })));
+        assert!(conditions
+            .contains(&serde_json::json!({ "x-amz-meta-account-id": "user.usr.example.org" })));
+        assert!(conditions.contains(&serde_json::json!(["content-length-range", 1, 1024])));
+    }
+
+    #[test]
+    fn presigned_post_v2_virtual_hosted_test() {
+        let mut client = Client::new(
+            "test",
+            CredentialsProvider::new_static("key", "secret", None),
+            "region",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        );
+        client.set_signing(SignatureVersion::V2, AddressingStyle::VirtualHosted);
+
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let post = client
+            .presigned_post_at(
+                &AwsCredentials::new("key", "secret", Some("token".to_owned()), None),
+                now,
+                "bucket",
+                "foo.bar",
+                &PostPolicyConditions::default(),
+                &Duration::from_secs(300),
+            )
+            .expect("presigned post");
+
+        assert_eq!(post.url, "https://bucket.s3.example.org/");
+        assert_eq!(post.fields["AWSAccessKeyId"], "key");
+        assert_eq!(post.fields["x-amz-security-token"], "token");
+        assert_eq!(
+            post.fields["signature"],
+            signature_v2(
+                &AwsCredentials::new("key", "secret", None, None),
+                post.fields["policy"].as_bytes()
+            )
+        );
+        assert!(!post.fields.contains_key("x-amz-credential"));
+    }
+
+    #[test]
+    fn copy_part_ranges_test() {
+        let ranges = copy_part_ranges(2 * COPY_PART_SIZE + 1).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, COPY_PART_SIZE - 1),
+                (COPY_PART_SIZE, 2 * COPY_PART_SIZE - 1),
+                (2 * COPY_PART_SIZE, 2 * COPY_PART_SIZE),
+            ]
+        );
+
+        // The largest S3 object fits the limit of parts of a multipart upload.
+        let max_object_size = 5 * 1024 * 1024 * 1024 * 1024;
+        assert!(copy_part_ranges(max_object_size).count() <= 10000);
     }
 }
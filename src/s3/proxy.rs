@@ -0,0 +1,617 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures::future::join_all;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::app::util::{ProxyHealthCheck, ProxyHost, ProxyKind, ProxyStrategy};
+
+const DEFAULT_ROUTE: &str = "default";
+const CONTINENT_PREFIX: &str = "continent:";
+const COUNTRY_PREFIX: &str = "country:";
+const ASN_PREFIX: &str = "asn:";
+
+/// A location of a client resolved of its ip address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Location {
+    /// Lowercase ISO 3166-1 country code.
+    pub country: Option<String>,
+    /// Lowercase continent code.
+    pub continent: Option<String>,
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+}
+
+impl Location {
+    /// Route keys from the most specific to the least specific one.
+    fn routes(&self) -> impl Iterator<Item = String> + '_ {
+        let asn = self.asn.map(|asn| format!("{}{}", ASN_PREFIX, asn));
+        let country = self.country.clone();
+        let continent = self
+            .continent
+            .as_ref()
+            .map(|continent| format!("{}{}", CONTINENT_PREFIX, continent));
+
+        asn.into_iter()
+            .chain(country)
+            .chain(continent)
+            .chain(Some(DEFAULT_ROUTE.to_owned()))
+    }
+}
+
+/// Properties of a signed URL a proxy host selection may stick to.
+#[derive(Clone, Copy, Debug)]
+pub struct Affinity<'a> {
+    /// `{bucket}/{object}` path of the object.
+    pub object: &'a str,
+    pub account: &'a str,
+}
+
+/// Normalizes a key of `proxy_hosts`: a country code optionally prefixed
+/// with `country:`, `continent:` prefixed continent code, `asn:` prefixed
+/// autonomous system number or `default`.
+fn route_key(key: &str) -> String {
+    let key = key.to_lowercase();
+    match key.strip_prefix(COUNTRY_PREFIX) {
+        Some(country) => country.to_owned(),
+        None => key,
+    }
+}
+
+/// A proxy host expanded of an alias range.
+#[derive(Debug)]
+pub struct ProxyTarget {
+    pub host: String,
+    pub weight: u32,
+    pub kind: ProxyKind,
+    health: Arc<Health>,
+}
+
+impl ProxyTarget {
+    fn selection(&self) -> Selection<'_> {
+        match self.kind {
+            ProxyKind::Proxy => Selection::Host(&self.host),
+            ProxyKind::Cdn => Selection::Cdn(&self.host),
+        }
+    }
+}
+
+/// Health of a host, it changes after a number of consecutive
+/// check results opposite to the current one.
+#[derive(Debug)]
+struct Health {
+    healthy: AtomicBool,
+    /// Consecutive check results opposite to the current health.
+    streak: AtomicU32,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Records a check result, returns `true` if health has changed.
+    fn record(&self, is_healthy: bool, config: &ProxyHealthCheck) -> bool {
+        if self.is_healthy() == is_healthy {
+            self.streak.store(0, Ordering::Relaxed);
+            return false;
+        }
+
+        let threshold = if is_healthy {
+            config.healthy_threshold
+        } else {
+            config.unhealthy_threshold
+        };
+        if self.streak.fetch_add(1, Ordering::Relaxed) + 1 < threshold {
+            return false;
+        }
+
+        self.streak.store(0, Ordering::Relaxed);
+        self.healthy.store(is_healthy, Ordering::Relaxed);
+        true
+    }
+}
+
+/// A result of proxy host selection for a location.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selection<'a> {
+    /// No proxy hosts are configured for the location.
+    NotConfigured,
+    Host(&'a str),
+    /// A CDN host URLs of reads are signed for with a CDN token.
+    Cdn(&'a str),
+    /// All proxy hosts matching the location are unhealthy.
+    Unavailable,
+}
+
+/// Proxy hosts of a route matching a location.
+#[derive(Debug, Serialize)]
+pub struct RouteHosts<'a> {
+    pub route: String,
+    pub hosts: Vec<HostStatus<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostStatus<'a> {
+    pub host: &'a str,
+    pub weight: u32,
+    pub kind: ProxyKind,
+    pub healthy: bool,
+}
+
+/// Proxy hosts by a route key with their health status shared between routes.
+#[derive(Debug, Default)]
+pub struct ProxyHosts {
+    by_route: BTreeMap<String, Vec<ProxyTarget>>,
+    health: BTreeMap<String, Arc<Health>>,
+    strategy: ProxyStrategy,
+    counter: AtomicUsize,
+}
+
+impl ProxyHosts {
+    pub fn new(proxy_hosts: &HashMap<String, Vec<ProxyHost>>, strategy: ProxyStrategy) -> Self {
+        let mut by_route: BTreeMap<String, Vec<ProxyTarget>> = BTreeMap::new();
+        let mut health: BTreeMap<String, Arc<Health>> = BTreeMap::new();
+
+        for (route, hosts) in proxy_hosts {
+            for host in hosts {
+                let names = host
+                    .alias_range_upper_bound
+                    .map(|upper_bound| {
+                        (1..=upper_bound)
+                            .map(|idx| format!("{idx}.{}", host.base))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or(vec![host.base.to_owned()]);
+
+                for name in names {
+                    let target_health = health
+                        .entry(name.clone())
+                        .or_insert_with(|| Arc::new(Health::new()))
+                        .clone();
+
+                    by_route
+                        .entry(route_key(route))
+                        .or_default()
+                        .push(ProxyTarget {
+                            host: name,
+                            weight: host.weight,
+                            kind: host.kind,
+                            health: target_health,
+                        });
+                }
+            }
+        }
+
+        Self {
+            by_route,
+            health,
+            strategy,
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.health.is_empty()
+    }
+
+    /// Picks a healthy proxy host of the most specific route of the location
+    /// falling back to less specific ones if all hosts of the route are unhealthy.
+    pub fn select(&self, location: &Location, affinity: Affinity) -> Selection<'_> {
+        let key = match self.strategy {
+            ProxyStrategy::RoundRobin => None,
+            ProxyStrategy::Object => Some(affinity.object),
+            ProxyStrategy::Account => Some(affinity.account),
+        };
+
+        let mut selection = Selection::NotConfigured;
+        for route in location.routes() {
+            selection = match self.select_route(&route, key) {
+                selection @ (Selection::Host(_) | Selection::Cdn(_)) => return selection,
+                Selection::Unavailable => Selection::Unavailable,
+                Selection::NotConfigured => selection,
+            };
+        }
+
+        selection
+    }
+
+    /// Proxy hosts of configured routes matching the location
+    /// in order of `select` trying them.
+    pub fn routes(&self, location: &Location) -> Vec<RouteHosts<'_>> {
+        location
+            .routes()
+            .filter_map(|route| {
+                let hosts = self
+                    .by_route
+                    .get(&route)?
+                    .iter()
+                    .map(|target| HostStatus {
+                        host: &target.host,
+                        weight: target.weight,
+                        kind: target.kind,
+                        healthy: target.health.is_healthy(),
+                    })
+                    .collect();
+                Some(RouteHosts { route, hosts })
+            })
+            .collect()
+    }
+
+    pub fn strategy(&self) -> ProxyStrategy {
+        self.strategy
+    }
+
+    /// Picks a healthy proxy host of the route with weighted rendezvous hashing
+    /// of the key if any or with weighted round-robin otherwise.
+    fn select_route(&self, route: &str, key: Option<&str>) -> Selection<'_> {
+        let targets = match self.by_route.get(route) {
+            Some(targets) if !targets.is_empty() => targets,
+            _ => return Selection::NotConfigured,
+        };
+
+        let healthy = || {
+            targets
+                .iter()
+                .filter(|target| target.weight > 0 && target.health.is_healthy())
+        };
+
+        if let Some(key) = key {
+            return healthy()
+                .map(|target| (rendezvous_score(key, target), target))
+                .max_by(|(a, _), (b, _)| a.total_cmp(b))
+                .map(|(_, target)| target.selection())
+                .unwrap_or(Selection::Unavailable);
+        }
+
+        let total = healthy()
+            .map(|target| target.weight as usize)
+            .sum::<usize>();
+        if total == 0 {
+            return Selection::Unavailable;
+        }
+
+        let mut idx = self.counter.fetch_add(1, Ordering::Relaxed) % total;
+        for target in healthy() {
+            let weight = target.weight as usize;
+            if idx < weight {
+                return target.selection();
+            }
+            idx -= weight;
+        }
+
+        // Health may change in between, the first healthy one is good enough.
+        healthy()
+            .next()
+            .map(|target| target.selection())
+            .unwrap_or(Selection::Unavailable)
+    }
+
+    /// Checks each host with a `HEAD` request of the path,
+    /// the host passes the check if it responds without a server error.
+    /// Health of the host changes once it passes or fails
+    /// the configured number of consecutive checks.
+    pub async fn check(&self, http: &reqwest::Client, scheme: &str, config: &ProxyHealthCheck) {
+        let checks = self.health.iter().map(|(host, health)| async move {
+            let url = format!("{}://{}{}", scheme, host, config.path);
+            let is_healthy = match http.head(&url).send().await {
+                Ok(resp) => !resp.status().is_server_error(),
+                Err(_) => false,
+            };
+
+            if health.record(is_healthy, config) {
+                if is_healthy {
+                    info!("proxy host {} is healthy again", host);
+                } else {
+                    warn!("proxy host {} is unhealthy, skipping it", host);
+                }
+            }
+        });
+
+        join_all(checks).await;
+    }
+
+    /// Health of each host.
+    pub fn health(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.health
+            .iter()
+            .map(|(host, health)| (host.as_str(), health.is_healthy()))
+    }
+
+    #[cfg(test)]
+    pub fn hosts(&self) -> BTreeMap<String, Vec<String>> {
+        self.by_route
+            .iter()
+            .map(|(route, targets)| {
+                let hosts = targets.iter().map(|target| target.host.clone()).collect();
+                (route.clone(), hosts)
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn set_healthy(&self, host: &str, healthy: bool) {
+        self.health[host].healthy.store(healthy, Ordering::Relaxed);
+    }
+}
+
+/// A score of the target for the key, the key sticks to a target with the highest one.
+/// Only keys of a target becoming unhealthy move to other targets then.
+fn rendezvous_score(key: &str, target: &ProxyTarget) -> f64 {
+    let hash = stable_hash(key.as_bytes(), target.host.as_bytes());
+    // A uniform value in (0, 1) of the 53 bits a double can hold.
+    let uniform = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    target.weight as f64 / -uniform.ln()
+}
+
+/// FNV-1a with a splitmix64 finalizer. Unlike `DefaultHasher` it is stable
+/// across releases so that every instance picks the same host for a key.
+fn stable_hash(key: &[u8], host: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.iter().chain(b"\0").chain(host) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_hosts() -> ProxyHosts {
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "RU".to_string(),
+            vec![
+                ProxyHost {
+                    base: "a.example.org".to_string(),
+                    alias_range_upper_bound: None,
+                    weight: 3,
+                    kind: ProxyKind::Proxy,
+                },
+                ProxyHost {
+                    base: "b.example.org".to_string(),
+                    alias_range_upper_bound: None,
+                    weight: 1,
+                    kind: ProxyKind::Proxy,
+                },
+            ],
+        );
+        ProxyHosts::new(&hosts, ProxyStrategy::RoundRobin)
+    }
+
+    const AFFINITY: Affinity = Affinity {
+        object: "bucket/object",
+        account: "user.usr.example.org",
+    };
+
+    fn location(country: &str, continent: &str, asn: Option<u32>) -> Location {
+        Location {
+            country: Some(country.to_string()),
+            continent: Some(continent.to_string()),
+            asn,
+        }
+    }
+
+    #[test]
+    fn select_weighted() {
+        let hosts = proxy_hosts();
+        let ru = location("ru", "eu", None);
+
+        let selected = (0..8)
+            .map(|_| hosts.select(&ru, AFFINITY))
+            .collect::<Vec<_>>();
+        let count = |host| {
+            selected
+                .iter()
+                .filter(|s| **s == Selection::Host(host))
+                .count()
+        };
+        assert_eq!(count("a.example.org"), 6);
+        assert_eq!(count("b.example.org"), 2);
+        assert_eq!(
+            hosts.select(&location("es", "eu", None), AFFINITY),
+            Selection::NotConfigured
+        );
+    }
+
+    #[test]
+    fn select_skips_unhealthy() {
+        let hosts = proxy_hosts();
+        let ru = location("ru", "eu", None);
+
+        hosts.set_healthy("a.example.org", false);
+        for _ in 0..4 {
+            assert_eq!(
+                hosts.select(&ru, AFFINITY),
+                Selection::Host("b.example.org")
+            );
+        }
+
+        hosts.set_healthy("b.example.org", false);
+        assert_eq!(hosts.select(&ru, AFFINITY), Selection::Unavailable);
+    }
+
+    #[test]
+    fn select_most_specific_route() {
+        let host = |base: &str| {
+            vec![ProxyHost {
+                base: base.to_string(),
+                alias_range_upper_bound: None,
+                weight: 1,
+                kind: ProxyKind::Proxy,
+            }]
+        };
+        let mut config = HashMap::new();
+        config.insert("asn:64500".to_string(), host("asn.example.org"));
+        config.insert("Country:RU".to_string(), host("ru.example.org"));
+        config.insert("continent:EU".to_string(), host("eu.example.org"));
+        config.insert("default".to_string(), host("default.example.org"));
+        let hosts = ProxyHosts::new(&config, ProxyStrategy::RoundRobin);
+
+        let select = |location: &Location| match hosts.select(location, AFFINITY) {
+            Selection::Host(host) => host.to_string(),
+            selection => panic!("unexpected selection = {:?}", selection),
+        };
+        assert_eq!(
+            select(&location("ru", "eu", Some(64500))),
+            "asn.example.org"
+        );
+        assert_eq!(select(&location("ru", "eu", Some(1))), "ru.example.org");
+        assert_eq!(select(&location("es", "eu", None)), "eu.example.org");
+        assert_eq!(select(&location("us", "na", None)), "default.example.org");
+        assert_eq!(select(&Location::default()), "default.example.org");
+
+        hosts.set_healthy("ru.example.org", false);
+        assert_eq!(select(&location("ru", "eu", None)), "eu.example.org");
+    }
+
+    #[test]
+    fn select_sticky() {
+        let mut config = HashMap::new();
+        config.insert(
+            "ru".to_string(),
+            vec![ProxyHost {
+                base: "example.org".to_string(),
+                alias_range_upper_bound: Some(8),
+                weight: 1,
+                kind: ProxyKind::Proxy,
+            }],
+        );
+        let hosts = ProxyHosts::new(&config, ProxyStrategy::Object);
+        let ru = location("ru", "eu", None);
+        let affinity = |object| Affinity {
+            object,
+            account: "user.usr.example.org",
+        };
+        let select = |object| match hosts.select(&ru, affinity(object)) {
+            Selection::Host(host) => host.to_string(),
+            selection => panic!("unexpected selection = {:?}", selection),
+        };
+
+        let objects = (0..64)
+            .map(|idx| format!("bucket/{}", idx))
+            .collect::<Vec<_>>();
+        let before = objects.iter().map(|o| select(o)).collect::<Vec<_>>();
+        assert_eq!(
+            objects.iter().map(|o| select(o)).collect::<Vec<_>>(),
+            before
+        );
+        assert!(
+            before
+                .iter()
+                .collect::<std::collections::BTreeSet<_>>()
+                .len()
+                > 1
+        );
+
+        // Only objects of the unhealthy host move to other hosts.
+        hosts.set_healthy("1.example.org", false);
+        for (object, host) in objects.iter().zip(&before) {
+            let selected = select(object);
+            if host == "1.example.org" {
+                assert_ne!(selected, *host);
+            } else {
+                assert_eq!(selected, *host);
+            }
+        }
+    }
+
+    #[test]
+    fn routes_of_location() {
+        let hosts = proxy_hosts();
+        hosts.set_healthy("b.example.org", false);
+
+        let routes = hosts.routes(&location("ru", "eu", Some(64500)));
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].route, "ru");
+        let status = routes[0]
+            .hosts
+            .iter()
+            .map(|status| (status.host, status.weight, status.healthy))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            status,
+            vec![("a.example.org", 3, true), ("b.example.org", 1, false)]
+        );
+
+        assert!(hosts.routes(&location("es", "eu", None)).is_empty());
+    }
+
+    #[test]
+    fn health_hysteresis() {
+        let config = ProxyHealthCheck {
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+            ..Default::default()
+        };
+        let health = Health::new();
+
+        // A failure in between resets the streak.
+        assert!(!health.record(false, &config));
+        assert!(!health.record(false, &config));
+        assert!(!health.record(true, &config));
+        assert!(!health.record(false, &config));
+        assert!(!health.record(false, &config));
+        assert!(health.is_healthy());
+        assert!(health.record(false, &config));
+        assert!(!health.is_healthy());
+
+        assert!(!health.record(true, &config));
+        assert!(!health.is_healthy());
+        assert!(health.record(true, &config));
+        assert!(health.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn check_unreachable_host() {
+        // The port is released right away, so connections are refused.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("address");
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "ru".to_string(),
+            vec![ProxyHost {
+                base: addr.to_string(),
+                alias_range_upper_bound: None,
+                weight: 1,
+                kind: ProxyKind::Proxy,
+            }],
+        );
+        let hosts = ProxyHosts::new(&hosts, ProxyStrategy::RoundRobin);
+        let config = ProxyHealthCheck {
+            unhealthy_threshold: 2,
+            ..Default::default()
+        };
+        let http = reqwest::Client::new();
+
+        let ru = location("ru", "eu", None);
+
+        hosts.check(&http, "http", &config).await;
+        assert_eq!(
+            hosts.select(&ru, AFFINITY),
+            Selection::Host(&addr.to_string())
+        );
+
+        hosts.check(&http, "http", &config).await;
+        assert_eq!(hosts.select(&ru, AFFINITY), Selection::Unavailable);
+    }
+}
@@ -1,5 +1,6 @@
 use serde::de;
 use serde::de::Visitor;
+use std::convert::TryFrom;
 use std::fmt;
 use std::time::Duration;
 
@@ -18,4 +19,60 @@ impl<'de> Visitor<'de> for DurationVisitor {
     {
         Ok(Duration::new(v, 0))
     }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        u64::try_from(v)
+            .map(|v| Duration::new(v, 0))
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+}
+
+struct OptionalDurationVisitor;
+
+impl<'de> Visitor<'de> for OptionalDurationVisitor {
+    type Value = Option<Duration>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "an optional u64")
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, d: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        duration(d).map(Some)
+    }
+}
+
+/// Deserializes a number of seconds into `Duration`.
+pub fn duration<'de, D>(d: D) -> Result<Duration, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    d.deserialize_u64(DurationVisitor)
+}
+
+/// Deserializes an optional number of seconds into `Option<Duration>`.
+pub fn optional_duration<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    d.deserialize_option(OptionalDurationVisitor)
 }